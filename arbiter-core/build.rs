@@ -0,0 +1,12 @@
+fn main() {
+  #[cfg(feature = "grpc")]
+  {
+    // Use the vendored `protoc` unless `PROTOC` names another, so builds need nothing installed.
+    if std::env::var_os("PROTOC").is_none() {
+      let protoc =
+        protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc for this host");
+      std::env::set_var("PROTOC", protoc);
+    }
+    tonic_build::compile_protos("proto/arbiter.proto").expect("failed to compile arbiter.proto");
+  }
+}
@@ -0,0 +1,193 @@
+//! A standard load for performance work on agents and networks.
+//!
+//! A generator broadcasts `--messages` loads of `--size` bytes to `--fanout` worker agents over an
+//! [`InMemory`] network. Each worker replies to a `--reply-ratio` share of the loads, and the run
+//! reports throughput along with latency percentiles for delivery (generator to worker) and round
+//! trips (generator to worker and back).
+//!
+//! ```sh
+//! cargo run --release --example stress -- --fanout 8 --size 256 --reply-ratio 0.1
+//! ```
+//!
+//! Loads are sent in windows that fit in the broadcast channel, and the generator waits for each
+//! window to be handled before sending the next, so no envelope is lost to a lagging receiver.
+
+use std::{
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+use arbiter_core::{agent::Agent, handler::Envelope, network::memory::InMemory, prelude::*};
+
+/// How many envelopes one window may put on the broadcast channel, which holds 1024.
+const WINDOW_ENVELOPES: usize = 512;
+
+#[derive(Debug, Clone, Copy)]
+struct Config {
+  fanout:      usize,
+  size:        usize,
+  reply_ratio: f64,
+  messages:    usize,
+}
+
+impl Config {
+  fn from_args() -> Self {
+    let mut config =
+      Self { fanout: 4, size: 128, reply_ratio: 0.1, messages: 100_000 };
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+      let value = args.next().unwrap_or_else(|| panic!("missing value for {flag}"));
+      match flag.as_str() {
+        "--fanout" => config.fanout = parse(&flag, &value),
+        "--size" => config.size = parse(&flag, &value),
+        "--reply-ratio" => config.reply_ratio = parse(&flag, &value),
+        "--messages" => config.messages = parse(&flag, &value),
+        _ => panic!("unknown flag {flag}"),
+      }
+    }
+    config
+  }
+
+  /// Whether workers reply to the load with the given id. Spreads replies evenly over the run.
+  fn replies_to(&self, id: u64) -> bool {
+    (id as f64 * self.reply_ratio).floor() != ((id + 1) as f64 * self.reply_ratio).floor()
+  }
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> T {
+  value.parse().unwrap_or_else(|_| panic!("invalid value for {flag}: {value}"))
+}
+
+#[derive(Debug)]
+struct Load {
+  id:      u64,
+  sent_at: Instant,
+  #[allow(dead_code)]
+  body:    Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Ack {
+  sent_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+  handled:     AtomicU64,
+  deliveries:  Mutex<Vec<Duration>>,
+  round_trips: Mutex<Vec<Duration>>,
+}
+
+struct Worker {
+  config: Config,
+  stats:  Arc<Stats>,
+}
+
+impl LifeCycle for Worker {
+  type StartMessage = ();
+  type StopMessage = ();
+
+  fn on_start(&mut self) -> Self::StartMessage {}
+
+  fn on_stop(&mut self, _reason: &ShutdownReason) -> Self::StopMessage {}
+}
+
+impl Handler<Load> for Worker {
+  type Reply = Ack;
+
+  fn handle(&mut self, message: &Load) -> Option<Ack> {
+    self.stats.deliveries.lock().unwrap().push(message.sent_at.elapsed());
+    self.stats.handled.fetch_add(1, Ordering::Release);
+    self.config.replies_to(message.id).then_some(Ack { sent_at: message.sent_at })
+  }
+}
+
+fn report(name: &str, samples: &mut [Duration]) {
+  if samples.is_empty() {
+    println!("{name:>11}: no samples");
+    return;
+  }
+  samples.sort_unstable();
+  let percentile = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+  println!(
+    "{name:>11}: p50 {:?}  p90 {:?}  p99 {:?}  max {:?}  ({} samples)",
+    percentile(0.5),
+    percentile(0.9),
+    percentile(0.99),
+    samples[samples.len() - 1],
+    samples.len()
+  );
+}
+
+#[tokio::main]
+async fn main() {
+  let config = Config::from_args();
+  let stats = Arc::new(Stats::default());
+  let network = InMemory::new();
+
+  let mut workers = Vec::with_capacity(config.fanout);
+  for _ in 0..config.fanout {
+    let worker = Worker { config, stats: stats.clone() };
+    let mut worker = Agent::new_join_network(worker, &network).with_handler::<Load>().process();
+    worker.start().await;
+    workers.push(worker);
+  }
+
+  let acks = Arc::new(AtomicU64::new(0));
+  let mut listener = network.join();
+  let collector = tokio::spawn({
+    let (stats, acks) = (stats.clone(), acks.clone());
+    async move {
+      while let Some(envelope) = listener.receive().await {
+        if let Some(ack) = envelope.unpackage::<Ack>() {
+          stats.round_trips.lock().unwrap().push(ack.sent_at.elapsed());
+          acks.fetch_add(1, Ordering::Release);
+        }
+      }
+    }
+  });
+
+  let window = (WINDOW_ENVELOPES / (1 + config.fanout)).max(1);
+  let fanout = config.fanout as u64;
+  let (mut sent, mut expected_acks) = (0u64, 0u64);
+  let start = Instant::now();
+  while sent < config.messages as u64 {
+    let end = (sent + window as u64).min(config.messages as u64);
+    for id in sent..end {
+      let load = Load { id, sent_at: Instant::now(), body: vec![0; config.size] };
+      network.send(Envelope::package(load)).await;
+      if config.replies_to(id) {
+        expected_acks += fanout;
+      }
+    }
+    sent = end;
+    while stats.handled.load(Ordering::Acquire) < sent * fanout
+      || acks.load(Ordering::Acquire) < expected_acks
+    {
+      tokio::task::yield_now().await;
+    }
+  }
+  let elapsed = start.elapsed();
+
+  for mut worker in workers {
+    worker.stop().await;
+    worker.join().await;
+  }
+  collector.abort();
+
+  let deliveries = sent * fanout;
+  println!(
+    "fanout {}  size {}B  reply ratio {}  window {window}",
+    config.fanout, config.size, config.reply_ratio
+  );
+  let throughput = deliveries as f64 / elapsed.as_secs_f64();
+  println!(
+    "{sent} loads, {deliveries} deliveries, {expected_acks} replies in {elapsed:?} \
+     ({throughput:.0} deliveries/s)"
+  );
+  report("delivery", &mut stats.deliveries.lock().unwrap());
+  report("round trip", &mut stats.round_trips.lock().unwrap());
+}
@@ -1,42 +1,85 @@
-use std::{any::TypeId, collections::HashMap, fmt::Debug};
+use std::{
+  any::{Any, TypeId},
+  collections::{HashMap, VecDeque},
+  fmt::Debug,
+  panic::AssertUnwindSafe,
+  sync::{atomic::Ordering, Arc},
+  time::Instant,
+};
 
 use tokio::task::JoinHandle;
+use tracing::Instrument;
 
+#[cfg(feature = "signing")]
+use crate::signing::{self, KeyDirectory, SignedAddress, SignedPayload, SigningKey};
 use crate::{
+  clock::{ClockSource, TokioClock},
   handler::{
-    create_handler, Envelope, HandleResult, Handler, Message, MessageHandlerFn, Package,
-    Unpacackage,
+    Delivery, Envelope, HandleResult, Handler, HandlerCounters, HandlerInfo, Message, Package,
+    RegisteredHandler, Unpacackage,
+  },
+  network::{
+    registry::{Registry, RegistryError},
+    Connection, Generateable, Network, NetworkEvent,
   },
-  network::{memory::InMemory, Connection, Generateable, Network},
+  rate_limit::{OverLimitPolicy, RateLimit, TokenBucket},
 };
 
 pub struct Agent<L: LifeCycle, N: Network> {
-  pub name:   Option<String>,
-  state:      State,
-  inner:      L,
-  connection: Connection<N>,
-  handlers:   HashMap<TypeId, MessageHandlerFn<N>>,
+  pub name:     Option<String>,
+  state:        State,
+  inner:        L,
+  connection:   Connection<N>,
+  handlers:     HashMap<TypeId, RegisteredHandler<N>>,
+  clock:        Arc<dyn ClockSource>,
+  rate_limiter: Option<TokenBucket>,
+  /// Outbound envelopes waiting for the rate limit under [`OverLimitPolicy::Queue`].
+  outbox:       VecDeque<Envelope<N>>,
+  panic_policy: PanicPolicy,
+  sign:         Option<SignFn<N>>,
+  verify:       Option<VerifyFn<N>>,
+  shutdown:     Option<ShutdownReason>,
+  registry:     Option<Registry<N::Address>>,
 }
 
+type SignFn<N> = Box<dyn Fn(&mut Envelope<N>) + Send + Sync>;
+type VerifyFn<N> = Box<dyn Fn(&Envelope<N>) -> bool + Send + Sync>;
+
 impl<L: LifeCycle, N: Network + Debug> Agent<L, N> {
   pub fn new(agent_inner: L) -> Self {
     let address = N::Address::generate();
     Self {
-      name:       None,
-      state:      State::Stopped,
-      inner:      agent_inner,
-      connection: Connection::<N>::new(address),
-      handlers:   HashMap::new(),
+      name:         None,
+      state:        State::Stopped,
+      inner:        agent_inner,
+      connection:   Connection::<N>::new(address),
+      handlers:     HashMap::new(),
+      clock:        Arc::new(TokioClock::new()),
+      rate_limiter: None,
+      outbox:       VecDeque::new(),
+      panic_policy: PanicPolicy::Propagate,
+      sign:         None,
+      verify:       None,
+      shutdown:     None,
+      registry:     None,
     }
   }
 
   pub fn new_join_network(agent_inner: L, network: &N) -> Self {
     Self {
-      name:       None,
-      state:      State::Stopped,
-      inner:      agent_inner,
-      connection: Connection { address: N::Address::generate(), network: network.join() },
-      handlers:   HashMap::new(),
+      name:         None,
+      state:        State::Stopped,
+      inner:        agent_inner,
+      connection:   Connection::joined(network, N::Address::generate()),
+      handlers:     HashMap::new(),
+      clock:        Arc::new(TokioClock::new()),
+      rate_limiter: None,
+      outbox:       VecDeque::new(),
+      panic_policy: PanicPolicy::Propagate,
+      sign:         None,
+      verify:       None,
+      shutdown:     None,
+      registry:     None,
     }
   }
 
@@ -49,10 +92,145 @@ impl<L: LifeCycle, N: Network + Debug> Agent<L, N> {
     M: Message,
     L: Handler<M>,
     N::Payload: Unpacackage<M> + Package<L::Reply>, {
-    self.handlers.insert(TypeId::of::<M>(), create_handler::<M, L, N>());
+    self.handlers.insert(TypeId::of::<M>(), RegisteredHandler::new::<M, L>());
+    self
+  }
+
+  /// Sets the clock that time-dependent behavior such as the agent's rate limit follows. Agents
+  /// use a [`TokioClock`] by default.
+  pub fn with_clock(mut self, clock: Arc<dyn ClockSource>) -> Self {
+    if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+      rate_limiter.set_clock(clock.clone());
+    }
+    self.clock = clock;
+    self
+  }
+
+  /// Limits how fast this agent may send messages onto its network.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `limit` queues messages but never refills, as [`TokenBucket::with_clock`] does.
+  pub fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+    self.rate_limiter = Some(TokenBucket::with_clock(limit, self.clock.clone()));
+    self
+  }
+
+  /// Registers the agent's address in `registry` under `name`. The agent unregisters itself when
+  /// it stops processing.
+  pub fn register_as(
+    mut self,
+    registry: Registry<N::Address>,
+    name: impl Into<String>,
+  ) -> Result<Self, RegistryError> {
+    registry.register(name, self.address())?;
+    self.registry = Some(registry);
+    Ok(self)
+  }
+
+  /// Sets what the agent does when one of its handlers panics.
+  pub const fn with_panic_policy(mut self, policy: PanicPolicy) -> Self {
+    self.panic_policy = policy;
+    self
+  }
+
+  /// Checks every inbound envelope with `verify` before it is dispatched. Envelopes it rejects are
+  /// dropped with a `tracing` warning.
+  pub fn with_verifier(
+    mut self,
+    verify: impl Fn(&Envelope<N>) -> bool + Send + Sync + 'static,
+  ) -> Self {
+    self.verify = Some(Box::new(verify));
     self
   }
 
+  /// Signs every envelope the agent sends with `key`, as sent from the agent's address.
+  #[cfg(feature = "signing")]
+  pub fn with_signing_key(mut self, key: SigningKey) -> Self
+  where
+    N::Address: SignedAddress,
+    N::Payload: SignedPayload, {
+    let address = self.address();
+    self.sign = Some(Box::new(move |envelope| signing::sign(&key, envelope, address)));
+    self
+  }
+
+  /// Drops every inbound envelope that is not signed by its sender's key in `directory`.
+  #[cfg(feature = "signing")]
+  pub fn with_key_directory(self, directory: Arc<KeyDirectory<N::Address>>) -> Self
+  where
+    N::Address: SignedAddress,
+    N::Payload: SignedPayload, {
+    self.with_verifier(move |envelope| directory.verify(envelope).is_ok())
+  }
+
+  /// The number of outbound messages discarded by the agent's rate limit.
+  pub fn dropped_messages(&self) -> u64 {
+    self.rate_limiter.as_ref().map_or(0, TokenBucket::dropped)
+  }
+
+  /// Sends `envelope` from this agent's address, or holds it in the outbox while the rate limit is
+  /// exhausted.
+  async fn send(&mut self, mut envelope: Envelope<N>) {
+    envelope.from = envelope.from.or(Some(self.address()));
+    if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+      // Envelopes already waiting go first.
+      if !self.outbox.is_empty() || !rate_limiter.try_acquire() {
+        match rate_limiter.limit().policy {
+          OverLimitPolicy::Queue => self.outbox.push_back(envelope),
+          OverLimitPolicy::Drop => {
+            tracing::warn!("exceeded the rate limit, dropping {envelope:?}");
+            self.inner.on_rate_limited(envelope.type_id);
+          },
+        }
+        return;
+      }
+    }
+    self.deliver(envelope).await;
+  }
+
+  /// Sends as many queued envelopes as the rate limit allows.
+  async fn release_queued(&mut self) {
+    while !self.outbox.is_empty() && self.rate_limiter.as_mut().is_none_or(TokenBucket::try_acquire)
+    {
+      if let Some(envelope) = self.outbox.pop_front() {
+        self.deliver(envelope).await;
+      }
+    }
+  }
+
+  /// Hands every event the connection has recorded to the behavior.
+  fn handle_network_events(&mut self) {
+    for event in self.connection.network.take_events() {
+      tracing::info!("network event: {event:?}");
+      self.inner.on_network_event(&event);
+    }
+  }
+
+  /// Discards every queued envelope, counting each as dropped by the rate limit.
+  fn discard_queued(&mut self) {
+    if self.outbox.is_empty() {
+      return;
+    }
+    tracing::warn!(
+      "stopping with {} rate-limited messages still queued, dropping them",
+      self.outbox.len()
+    );
+    if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+      rate_limiter.record_dropped(self.outbox.len() as u64);
+    }
+    for envelope in std::mem::take(&mut self.outbox) {
+      self.inner.on_rate_limited(envelope.type_id);
+    }
+  }
+
+  async fn deliver(&mut self, mut envelope: Envelope<N>) {
+    if let Some(sign) = &self.sign {
+      sign(&mut envelope);
+    }
+    self.connection.network.send(envelope).await;
+  }
+
   pub const fn address(&self) -> N::Address { self.connection.address }
 
   pub fn name(&self) -> Option<&str> { self.name.as_deref() }
@@ -64,6 +242,18 @@ impl<L: LifeCycle, N: Network + Debug> Agent<L, N> {
   pub const fn inner_mut(&mut self) -> &mut L { &mut self.inner }
 
   pub const fn state(&self) -> State { self.state }
+
+  pub fn clock(&self) -> &dyn ClockSource { self.clock.as_ref() }
+
+  /// Why the agent last stopped processing, or `None` if it never has.
+  pub const fn shutdown_reason(&self) -> Option<&ShutdownReason> { self.shutdown.as_ref() }
+
+  /// The agent's handlers, ordered by message type name.
+  pub fn handlers(&self) -> Vec<HandlerInfo> {
+    let mut handlers: Vec<_> = self.handlers.values().map(RegisteredHandler::info).collect();
+    handlers.sort_by_key(|info| info.message_type);
+    handlers
+  }
 }
 
 pub struct ProcessingAgent<L: LifeCycle, T: Network + Debug> {
@@ -71,6 +261,7 @@ pub struct ProcessingAgent<L: LifeCycle, T: Network + Debug> {
   pub address:                 T::Address,
   pub(crate) task:             JoinHandle<Agent<L, T>>,
   pub(crate) outer_controller: OuterController,
+  pub(crate) handlers:         Vec<(&'static str, Arc<HandlerCounters>)>,
 }
 
 impl<L: LifeCycle, T: Network + Debug> ProcessingAgent<L, T> {
@@ -95,6 +286,11 @@ impl<L: LifeCycle, T: Network + Debug> ProcessingAgent<L, T> {
     assert_eq!(state, State::Stopped);
   }
 
+  /// The agent's handlers as of now, ordered by message type name.
+  pub fn handlers(&self) -> Vec<HandlerInfo> {
+    self.handlers.iter().map(|(message_type, counters)| counters.info(message_type)).collect()
+  }
+
   pub async fn join(self) -> Agent<L, T> { self.task.await.unwrap() }
 }
 
@@ -104,6 +300,40 @@ pub enum State {
   Running,
 }
 
+/// Why an agent stopped processing messages. Passed to [`LifeCycle::on_stop`] and kept on the
+/// [`Agent`] returned by [`ProcessingAgent::join`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShutdownReason {
+  /// [`ProcessingAgent::stop`] was called.
+  Requested,
+  /// One of the agent's handlers returned [`HandleResult::Stop`].
+  Halted,
+  /// The [`ProcessingAgent`] controlling the agent was dropped.
+  ControllerDropped,
+  /// A handler panicked under [`PanicPolicy::Stop`]. Holds the panic message.
+  Panicked(String),
+}
+
+/// What an agent does when one of its handlers panics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+  /// Let the panic unwind through the agent's task, as if no policy were set.
+  #[default]
+  Propagate,
+  /// Log the panic, drop the message, and keep processing.
+  Skip,
+  /// Log the panic and stop the agent with [`ShutdownReason::Panicked`].
+  Stop,
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+  payload
+    .downcast_ref::<&str>()
+    .map(ToString::to_string)
+    .or_else(|| payload.downcast_ref::<String>().cloned())
+    .unwrap_or_else(|| "non-string panic payload".to_string())
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ControlSignal {
   Start,
@@ -143,23 +373,55 @@ pub trait LifeCycle: Send + Sync + 'static {
   type StartMessage: Message + Debug;
   type StopMessage: Message + Debug;
   fn on_start(&mut self) -> Self::StartMessage;
-  fn on_stop(&mut self) -> Self::StopMessage;
+  fn on_stop(&mut self, reason: &ShutdownReason) -> Self::StopMessage;
+
+  /// Called when the agent's connection reports a [`NetworkEvent`], such as losing its transport or
+  /// lagging behind, before the agent handles the next envelope.
+  fn on_network_event(&mut self, _event: &NetworkEvent) {}
+
+  /// Called when the agent's [`RateLimit`] discards an outbound message, with the message's type.
+  /// Under [`OverLimitPolicy::Drop`] that is every message sent while the limit is exhausted, and
+  /// under [`OverLimitPolicy::Queue`] every message still queued when the agent stops.
+  fn on_rate_limited(&mut self, _type_id: TypeId) {}
 }
 
-impl<L: LifeCycle> Agent<L, InMemory> {
-  pub fn process(mut self) -> ProcessingAgent<L, InMemory> {
+impl<L: LifeCycle, N: Network + Debug> Agent<L, N>
+where N::Payload: Package<L::StartMessage> + Package<L::StopMessage>
+{
+  pub fn process(mut self) -> ProcessingAgent<L, N> {
     let name = self.name.clone();
     let address = self.address();
+    let mut handlers: Vec<_> = self
+      .handlers
+      .values()
+      .map(|handler| (handler.message_type, handler.counters.clone()))
+      .collect();
+    handlers.sort_by_key(|(message_type, _)| *message_type);
     let controller = Controller::new();
     let mut inner_controller = controller.inner;
     let outer_controller = controller.outer;
+    let agent_span = tracing::info_span!(
+      "agent",
+      name = %name.as_deref().unwrap_or("unknown"),
+      behavior = std::any::type_name::<L>(),
+      %address
+    );
 
     let task = tokio::spawn(async move {
-      loop {
+      // How many envelopes addressed to this agent it has received, for the `handle` spans.
+      let mut sequence = 0u64;
+      let events = self.connection.network.event_signal();
+      let reason = loop {
         // ────────────────────────────────────────────────────────────────
         // Control-plane messages (START / STOP / GET_STATE)
         // ────────────────────────────────────────────────────────────────
         let prev_state = self.state;
+        let clock = self.clock.clone();
+        let release_at = self
+          .rate_limiter
+          .as_ref()
+          .filter(|_| !self.outbox.is_empty())
+          .map(TokenBucket::next_token_at);
         tokio::select! {
           biased;
           control_signal = inner_controller.instruction_receiver.recv() => {
@@ -167,60 +429,155 @@ impl<L: LifeCycle> Agent<L, InMemory> {
               Some(ControlSignal::Start) => {
                 self.state = State::Running;
                 inner_controller.state_sender.send(State::Running).await.unwrap();
-                let start_message = self.inner.on_start();
-                println!("sending start_message for agent {}", self.name.as_deref().unwrap_or("unknown"));
-                self.connection.network.send(Envelope::package(start_message)).await;
+                let start_message = tracing::debug_span!("on_start").in_scope(|| self.inner.on_start());
+                tracing::debug!("sending start message {start_message:?}");
+                self.send(Envelope::package(start_message)).await;
               },
               Some(ControlSignal::Stop) => {
                 self.state = State::Stopped;
                 inner_controller.state_sender.send(State::Stopped).await.unwrap();
-                let stop_message = self.inner.on_stop();
-                self.connection.network.send(Envelope::package(stop_message)).await;
-                break;
+                break ShutdownReason::Requested;
               },
               Some(ControlSignal::GetState) => {
                 inner_controller.state_sender.send(prev_state).await.unwrap();
               },
               None => {
-                break;
+                break ShutdownReason::ControllerDropped;
               },
             }
           }
           // ────────────────────────────────────────────────────────────────
+          // Rate-limited messages waiting for a token
+          // ────────────────────────────────────────────────────────────────
+          () = clock.sleep_until(release_at.unwrap_or_default()), if release_at.is_some() => {
+            self.release_queued().await;
+          }
+          // ────────────────────────────────────────────────────────────────
+          // Network events recorded while a receive is still in progress
+          // ────────────────────────────────────────────────────────────────
+          () = async { events.as_ref().unwrap().notified().await }, if events.is_some() => {
+            self.handle_network_events();
+          }
+          // ────────────────────────────────────────────────────────────────
           // Application messages coming from the transport
           // ────────────────────────────────────────────────────────────────
           message = self.connection.network.receive() => {
+            self.handle_network_events();
             if let Some(message) = message {
-              println!("received message {:?} for agent {}", message, self.name.as_deref().unwrap_or("unknown"));
+              if !message.to.includes(&self.address()) {
+                continue;
+              }
+              sequence += 1;
+              tracing::trace!(sequence, "received {message:?}");
+              if self.verify.as_ref().is_some_and(|verify| !verify(&message)) {
+                tracing::warn!(sequence, "dropping envelope that failed verification");
+                continue;
+              }
+              let sender = message.from;
               if let Some(handler) = self.handlers.get(&message.type_id) {
-                let reply = handler(&mut self.inner, message.payload);
-                println!("reply for agent {}", self.name.as_deref().unwrap_or("unknown"));
+                let counters = &handler.counters;
+                counters.handled.fetch_add(1, Ordering::Relaxed);
+                let span = tracing::debug_span!("handle", message_type = handler.message_type, sequence);
+                let reply = {
+                  // Not held across an await: the reply is sent under `instrument` below.
+                  let _entered = span.enter();
+                  let handle = &handler.handle;
+                  let started = Instant::now();
+                  let reply = match self.panic_policy {
+                    PanicPolicy::Propagate => Ok(handle(&mut self.inner, message.payload)),
+                    _ => std::panic::catch_unwind(AssertUnwindSafe(|| handle(&mut self.inner, message.payload))),
+                  };
+                  counters.record_busy(started.elapsed());
+                  match reply {
+                    Ok(reply) => reply,
+                    Err(payload) => {
+                      counters.panics.fetch_add(1, Ordering::Relaxed);
+                      let panic = panic_message(payload.as_ref());
+                      tracing::error!("handler panicked: {panic}");
+                      if self.panic_policy == PanicPolicy::Stop {
+                        break ShutdownReason::Panicked(panic);
+                      }
+                      continue;
+                    },
+                  }
+                };
                 match reply {
                   HandleResult::Message(message) => {
-                    println!("sending reply {:?} for agent {}", message, self.name.as_deref().unwrap_or("unknown"));
-                    self.connection.network.send(message).await;
+                    counters.replies.fetch_add(1, Ordering::Relaxed);
+                    span.in_scope(|| tracing::debug!("sending reply {message:?}"));
+                    self.send(message).instrument(span).await;
+                  },
+                  HandleResult::Reply(mut message) => {
+                    counters.replies.fetch_add(1, Ordering::Relaxed);
+                    if let Some(sender) = sender {
+                      message.to = Delivery::Unicast(sender);
+                    }
+                    span.in_scope(|| tracing::debug!("sending reply {message:?}"));
+                    self.send(message).instrument(span).await;
                   },
                   HandleResult::None => {},
-                  HandleResult::Stop => break,
+                  HandleResult::Stop => break ShutdownReason::Halted,
                 }
               }
             }
           }
         }
+      };
+
+      self.state = State::Stopped;
+      let span = tracing::debug_span!("on_stop", ?reason);
+      let stop_message = span.in_scope(|| self.inner.on_stop(&reason));
+      self.send(Envelope::package(stop_message)).await;
+      self.discard_queued();
+      self.connection.network.flush().await;
+      if let Some(registry) = self.registry.take() {
+        registry.unregister(self.address());
       }
-
+      self.shutdown = Some(reason);
       self
-    });
+    }.instrument(agent_span));
 
-    ProcessingAgent { name, address, task, outer_controller }
+    ProcessingAgent { name, address, task, outer_controller, handlers }
   }
 }
 
 #[cfg(test)]
 mod tests {
 
+  use std::time::Duration;
+
   use super::*;
-  use crate::fixtures::*;
+  use crate::{
+    clock::ManualClock,
+    fixtures::*,
+    network::{
+      memory::{InMemory, InMemoryAddress},
+      EventLog,
+    },
+  };
+
+  /// A network that never delivers anything, and records events only while a receive is pending.
+  #[derive(Debug, Default)]
+  struct Retrying {
+    events: EventLog,
+  }
+
+  impl Network for Retrying {
+    type Address = InMemoryAddress;
+    type Payload = Arc<dyn Message>;
+
+    fn new() -> Self { Self::default() }
+
+    fn join(&self) -> Self { Self { events: self.events.clone() } }
+
+    async fn send(&self, _envelope: Envelope<Self>) {}
+
+    async fn receive(&mut self) -> Option<Envelope<Self>> { std::future::pending().await }
+
+    fn take_events(&mut self) -> Vec<NetworkEvent> { self.events.take() }
+
+    fn event_signal(&self) -> Option<Arc<tokio::sync::Notify>> { Some(self.events.signal()) }
+  }
 
   #[tokio::test]
   async fn test_agent_lifecycle() {
@@ -237,6 +594,7 @@ mod tests {
     processing_agent.stop().await;
     let joined_agent = processing_agent.join().await;
     assert_eq!(joined_agent.state, State::Stopped);
+    assert_eq!(joined_agent.shutdown_reason(), Some(&ShutdownReason::Requested));
   }
 
   #[tokio::test]
@@ -288,4 +646,347 @@ mod tests {
     assert_eq!(agent.state, State::Stopped);
     assert_eq!(agent.inner.message_count, 2);
   }
+
+  #[tokio::test]
+  async fn test_handler_info() {
+    let agent = Agent::<Logger, InMemory>::new(Logger {
+      name:          "TestLogger".to_string(),
+      message_count: 0,
+    })
+    .with_handler::<TextMessage>()
+    .with_handler::<NumberMessage>();
+    let sender = agent.connection.network.sender.clone();
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    sender.send(Envelope::package(TextMessage { content: "Hello".to_string() }));
+    sender.send(Envelope::package(NumberMessage { value: 3 }));
+    sender.send(Envelope::package(NumberMessage { value: 4 }));
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+    let handlers = processing_agent.handlers();
+    assert_eq!(handlers.len(), 2);
+    assert!(handlers[0].message_type.ends_with("NumberMessage"));
+    assert_eq!(handlers[0].handled, 2);
+    assert!(handlers[1].message_type.ends_with("TextMessage"));
+    assert_eq!(handlers[1].handled, 1);
+    // `Logger` replies with `()` to every message.
+    assert_eq!((handlers[0].replies, handlers[1].replies), (2, 1));
+    assert_eq!(handlers[0].panics, 0);
+
+    processing_agent.stop().await;
+    let agent = processing_agent.join().await;
+    assert_eq!(agent.handlers(), handlers);
+  }
+
+  #[tokio::test]
+  #[tracing_test::traced_test]
+  async fn test_handlers_run_in_agent_spans() {
+    struct Traced;
+
+    impl LifeCycle for Traced {
+      type StartMessage = ();
+      type StopMessage = ();
+
+      fn on_start(&mut self) -> Self::StartMessage {}
+
+      fn on_stop(&mut self, _reason: &ShutdownReason) -> Self::StopMessage {}
+    }
+
+    impl Handler<TextMessage> for Traced {
+      type Reply = ();
+
+      fn handle(&mut self, message: &TextMessage) {
+        tracing::info!("handled {}", message.content);
+      }
+    }
+
+    let mut agent = Agent::<Traced, InMemory>::new(Traced).with_handler::<TextMessage>();
+    agent.set_name("worker");
+    let sender = agent.connection.network.sender.clone();
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    sender.send(Envelope::package(TextMessage { content: "hi".to_string() })).unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    processing_agent.stop().await;
+    processing_agent.join().await;
+
+    logs_assert(|lines: &[&str]| {
+      let line =
+        lines.iter().find(|line| line.contains("handled hi")).ok_or("handler not traced")?;
+      let spans = ["agent{name=worker behavior=", "Traced", "handle{message_type=", "sequence="];
+      if spans.iter().all(|span| line.contains(span)) {
+        Ok(())
+      } else {
+        Err(format!("handler traced outside its spans: {line}"))
+      }
+    });
+  }
+
+  #[tokio::test]
+  async fn test_rate_limit_drops_replies() {
+    let agent = Agent::<Logger, InMemory>::new(Logger {
+      name:          "TestLogger".to_string(),
+      message_count: 0,
+    })
+    .with_handler::<TextMessage>()
+    .with_rate_limit(RateLimit::new(0.0, 1).with_policy(OverLimitPolicy::Drop));
+    let sender = agent.connection.network.sender.clone();
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    sender.send(Envelope::package(TextMessage { content: "Hello".to_string() }));
+    sender.send(Envelope::package(TextMessage { content: "World".to_string() }));
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+    processing_agent.stop().await;
+    let agent = processing_agent.join().await;
+    assert_eq!(agent.inner.message_count, 2);
+    // The start message spends the only token, so both replies and the stop message are dropped.
+    assert_eq!(agent.dropped_messages(), 3);
+  }
+
+  #[tokio::test]
+  async fn test_rate_limit_queues_replies_without_blocking_control() {
+    let clock = Arc::new(ManualClock::new(Duration::from_secs(1_000)));
+    let agent = Agent::<Logger, InMemory>::new(Logger {
+      name:          "TestLogger".to_string(),
+      message_count: 0,
+    })
+    .with_handler::<TextMessage>()
+    .with_clock(clock.clone())
+    .with_rate_limit(RateLimit::new(1.0, 1));
+    let address = agent.address();
+    let sender = agent.connection.network.sender.clone();
+    let mut sent = sender.subscribe();
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    sender.send(Envelope::package(TextMessage { content: "Hello".to_string() }));
+    sender.send(Envelope::package(TextMessage { content: "World".to_string() }));
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    // Both replies wait for the clock, but the agent still answers its controller.
+    assert_eq!(processing_agent.state().await, State::Running);
+    let mut outbound = || {
+      std::iter::from_fn(|| sent.try_recv().ok())
+        .filter(|envelope| envelope.from == Some(address))
+        .count()
+    };
+    // Only the start message has gone out.
+    assert_eq!(outbound(), 1);
+
+    clock.advance(Duration::from_secs(1));
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(outbound(), 1);
+
+    processing_agent.stop().await;
+    let agent = processing_agent.join().await;
+    assert_eq!(agent.inner.message_count, 2);
+    // The second reply and the stop message were still queued.
+    assert_eq!(agent.dropped_messages(), 2);
+  }
+
+  #[tokio::test]
+  async fn test_registered_agent_unregisters_on_stop() {
+    let registry = Registry::new();
+    let agent = Agent::<Logger, InMemory>::new(Logger {
+      name:          "TestLogger".to_string(),
+      message_count: 0,
+    })
+    .register_as(registry.clone(), "logger")
+    .unwrap();
+    let address = agent.address();
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    assert_eq!(registry.resolve("logger"), Some(address));
+
+    processing_agent.stop().await;
+    processing_agent.join().await;
+    assert_eq!(registry.resolve("logger"), None);
+  }
+
+  #[tokio::test]
+  async fn test_verifier_drops_rejected_envelopes() {
+    let agent = Agent::<Logger, InMemory>::new(Logger {
+      name:          "TestLogger".to_string(),
+      message_count: 0,
+    })
+    .with_handler::<TextMessage>()
+    .with_verifier(|envelope| envelope.from.is_some());
+    let sender = agent.connection.network.sender.clone();
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    let mut attributed = Envelope::package(TextMessage { content: "Hello".to_string() });
+    attributed.from = Some(InMemoryAddress::generate());
+    sender.send(attributed);
+    sender.send(Envelope::package(TextMessage { content: "Anonymous".to_string() }));
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+    processing_agent.stop().await;
+    let agent = processing_agent.join().await;
+    assert_eq!(agent.inner.message_count, 1);
+  }
+
+  #[tokio::test]
+  async fn test_agent_drops_envelopes_for_other_addresses() {
+    let agent = Agent::<Logger, InMemory>::new(Logger {
+      name:          "TestLogger".to_string(),
+      message_count: 0,
+    })
+    .with_handler::<TextMessage>();
+    let (address, other) = (agent.address(), InMemoryAddress::generate());
+    let sender = agent.connection.network.sender.clone();
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    let text = |content: &str| Envelope::package(TextMessage { content: content.to_string() });
+    sender.send(text("Unicast").with_delivery(Delivery::Unicast(address)));
+    sender.send(text("Elsewhere").with_delivery(Delivery::Unicast(other)));
+    sender.send(text("Multicast").with_delivery(Delivery::Multicast(vec![other, address])));
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+    processing_agent.stop().await;
+    let agent = processing_agent.join().await;
+    assert_eq!(agent.inner.message_count, 2);
+  }
+
+  #[tokio::test]
+  async fn test_replies_go_back_to_the_sender() {
+    struct Responder;
+
+    impl LifeCycle for Responder {
+      type StartMessage = ();
+      type StopMessage = ();
+
+      fn on_start(&mut self) -> Self::StartMessage {}
+
+      fn on_stop(&mut self, _reason: &ShutdownReason) -> Self::StopMessage {}
+    }
+
+    impl Handler<TextMessage> for Responder {
+      type Reply = NumberMessage;
+
+      fn handle(&mut self, message: &TextMessage) -> HandleResult<NumberMessage> {
+        match message.content.as_str() {
+          "reply" => HandleResult::Reply(NumberMessage { value: 1 }),
+          _ => HandleResult::Message(NumberMessage { value: 2 }),
+        }
+      }
+    }
+
+    let agent = Agent::<Responder, InMemory>::new(Responder).with_handler::<TextMessage>();
+    let sender = agent.connection.network.sender.clone();
+    let mut receiver = sender.subscribe();
+    let other = InMemoryAddress::generate();
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    for content in ["reply", "message"] {
+      let mut envelope = Envelope::package(TextMessage { content: content.to_string() });
+      envelope.from = Some(other);
+      sender.send(envelope).unwrap();
+    }
+    sender.send(Envelope::package(TextMessage { content: "reply".to_string() })).unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    processing_agent.stop().await;
+    processing_agent.join().await;
+
+    let mut replies = Vec::new();
+    while let Ok(envelope) = receiver.try_recv() {
+      if let Some(reply) = envelope.unpackage::<NumberMessage>() {
+        replies.push((reply.value, envelope.to.clone()));
+      }
+    }
+    assert_eq!(replies, [
+      (1, Delivery::Unicast(other)),
+      (2, Delivery::Broadcast),
+      (1, Delivery::Broadcast)
+    ]);
+  }
+
+  struct Panicker;
+
+  impl LifeCycle for Panicker {
+    type StartMessage = ();
+    type StopMessage = ();
+
+    fn on_start(&mut self) -> Self::StartMessage {}
+
+    fn on_stop(&mut self, _reason: &ShutdownReason) -> Self::StopMessage {}
+  }
+
+  impl Handler<TextMessage> for Panicker {
+    type Reply = ();
+
+    fn handle(&mut self, message: &TextMessage) { panic!("{}", message.content) }
+  }
+
+  #[tokio::test]
+  async fn test_panic_policy_skip() {
+    let agent = Agent::<Panicker, InMemory>::new(Panicker)
+      .with_handler::<TextMessage>()
+      .with_panic_policy(PanicPolicy::Skip);
+    let sender = agent.connection.network.sender.clone();
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    sender.send(Envelope::package(TextMessage { content: "boom".to_string() }));
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    assert_eq!(processing_agent.state().await, State::Running);
+    let handler = &processing_agent.handlers()[0];
+    assert_eq!((handler.handled, handler.replies, handler.panics), (1, 0, 1));
+
+    processing_agent.stop().await;
+    let agent = processing_agent.join().await;
+    assert_eq!(agent.shutdown_reason(), Some(&ShutdownReason::Requested));
+  }
+
+  #[tokio::test]
+  async fn test_panic_policy_stop() {
+    let agent = Agent::<Panicker, InMemory>::new(Panicker)
+      .with_handler::<TextMessage>()
+      .with_panic_policy(PanicPolicy::Stop);
+    let sender = agent.connection.network.sender.clone();
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    sender.send(Envelope::package(TextMessage { content: "boom".to_string() }));
+
+    let agent = processing_agent.join().await;
+    assert_eq!(agent.state(), State::Stopped);
+    assert_eq!(agent.shutdown_reason(), Some(&ShutdownReason::Panicked("boom".to_string())));
+  }
+
+  #[tokio::test]
+  async fn test_network_events_arrive_while_receiving() {
+    struct Watcher {
+      events: Vec<NetworkEvent>,
+    }
+
+    impl LifeCycle for Watcher {
+      type StartMessage = ();
+      type StopMessage = ();
+
+      fn on_start(&mut self) -> Self::StartMessage {}
+
+      fn on_stop(&mut self, _reason: &ShutdownReason) -> Self::StopMessage {}
+
+      fn on_network_event(&mut self, event: &NetworkEvent) { self.events.push(event.clone()); }
+    }
+
+    let agent = Agent::<Watcher, Retrying>::new(Watcher { events: Vec::new() });
+    let events = agent.connection.network.events.clone();
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    let disconnected = NetworkEvent::Disconnected { reason: "gone".to_string() };
+    events.record(disconnected.clone());
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    processing_agent.stop().await;
+    let agent = processing_agent.join().await;
+    assert_eq!(agent.inner.events, [disconnected]);
+  }
 }
@@ -0,0 +1,222 @@
+//! Sources of time for agents.
+//!
+//! Anything in an agent that depends on time, such as its
+//! [`RateLimit`](crate::rate_limit::RateLimit), reads it from a [`ClockSource`] instead of the
+//! system clock. Live deployments use [`TokioClock`], which follows the wall clock. Simulations and
+//! backtests can use a [`StepClock`] that advances once per simulation step, a [`ManualClock`] set
+//! directly by a test, or a [`HistoricalClock`] that follows the timestamps of the data being
+//! replayed.
+//!
+//! Every clock reports time as a [`Duration`] since its epoch. For [`TokioClock`] the epoch is the
+//! Unix epoch; the other clocks start wherever they are told to. Waiting on a clock with
+//! [`ClockSource::sleep_until`] follows the clock too, so a wait on a [`ManualClock`] ends when the
+//! clock is advanced past it rather than after some amount of wall time.
+
+use std::{
+  fmt::Debug,
+  future::Future,
+  pin::Pin,
+  sync::atomic::{AtomicU64, Ordering},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::Notify;
+
+pub trait ClockSource: Send + Sync + Debug + 'static {
+  /// The current time, as a duration since the clock's epoch.
+  fn now(&self) -> Duration;
+
+  /// Waits until the clock reads at least `deadline`.
+  ///
+  /// By default this sleeps in tokio time for as long as the clock is behind, then checks again,
+  /// which suits clocks that follow the wall clock.
+  fn sleep_until(&self, deadline: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+    Box::pin(async move {
+      while let Some(remaining) = deadline.checked_sub(self.now()).filter(|d| !d.is_zero()) {
+        tokio::time::sleep(remaining).await;
+      }
+    })
+  }
+}
+
+fn nanos(duration: Duration) -> u64 { u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX) }
+
+/// Waits until `clock` reads at least `deadline`, checking again each time `moved` is notified.
+async fn wait_for_move(clock: &dyn ClockSource, moved: &Notify, deadline: Duration) {
+  loop {
+    let notified = moved.notified();
+    tokio::pin!(notified);
+    // Register before reading the clock, so a move in between is not missed.
+    notified.as_mut().enable();
+    if clock.now() >= deadline {
+      return;
+    }
+    notified.await;
+  }
+}
+
+/// The wall clock, advanced by the tokio runtime.
+///
+/// Time is read from [`tokio::time::Instant`], so pausing or advancing time in a tokio test moves
+/// this clock too.
+#[derive(Debug, Clone, Copy)]
+pub struct TokioClock {
+  origin: Duration,
+  start:  tokio::time::Instant,
+}
+
+impl TokioClock {
+  pub fn new() -> Self {
+    let origin = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Self { origin, start: tokio::time::Instant::now() }
+  }
+}
+
+impl Default for TokioClock {
+  fn default() -> Self { Self::new() }
+}
+
+impl ClockSource for TokioClock {
+  fn now(&self) -> Duration { self.origin + self.start.elapsed() }
+}
+
+/// A clock that advances a fixed amount each time [`StepClock::tick`] is called.
+#[derive(Debug)]
+pub struct StepClock {
+  step:        AtomicU64,
+  step_length: Duration,
+  moved:       Notify,
+}
+
+impl StepClock {
+  pub fn new(step_length: Duration) -> Self {
+    Self { step: AtomicU64::new(0), step_length, moved: Notify::new() }
+  }
+
+  /// Moves to the next step and returns its number.
+  pub fn tick(&self) -> u64 {
+    let step = self.step.fetch_add(1, Ordering::AcqRel) + 1;
+    self.moved.notify_waiters();
+    step
+  }
+
+  pub fn step(&self) -> u64 { self.step.load(Ordering::Acquire) }
+
+  pub const fn step_length(&self) -> Duration { self.step_length }
+}
+
+impl ClockSource for StepClock {
+  fn now(&self) -> Duration {
+    Duration::from_nanos(nanos(self.step_length).saturating_mul(self.step()))
+  }
+
+  fn sleep_until(&self, deadline: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+    Box::pin(wait_for_move(self, &self.moved, deadline))
+  }
+}
+
+/// A clock that only moves when it is set or advanced.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+  now:   AtomicU64,
+  moved: Notify,
+}
+
+impl ManualClock {
+  pub fn new(start: Duration) -> Self {
+    Self { now: AtomicU64::new(nanos(start)), moved: Notify::new() }
+  }
+
+  pub fn set(&self, now: Duration) {
+    self.now.store(nanos(now), Ordering::Release);
+    self.moved.notify_waiters();
+  }
+
+  pub fn advance(&self, by: Duration) {
+    self.now.fetch_add(nanos(by), Ordering::AcqRel);
+    self.moved.notify_waiters();
+  }
+}
+
+impl ClockSource for ManualClock {
+  fn now(&self) -> Duration { Duration::from_nanos(self.now.load(Ordering::Acquire)) }
+
+  fn sleep_until(&self, deadline: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+    Box::pin(wait_for_move(self, &self.moved, deadline))
+  }
+}
+
+/// A clock driven by the timestamps of historical data.
+///
+/// Each record fed into a backtest is passed to [`HistoricalClock::observe`], and the clock reads
+/// as the latest timestamp seen. Records that arrive out of order never move the clock backwards.
+#[derive(Debug, Default)]
+pub struct HistoricalClock {
+  latest: AtomicU64,
+  moved:  Notify,
+}
+
+impl HistoricalClock {
+  pub fn new() -> Self { Self::default() }
+
+  /// Moves the clock to `timestamp` if it is later than the current time, and returns the time.
+  pub fn observe(&self, timestamp: Duration) -> Duration {
+    let timestamp = nanos(timestamp);
+    let previous = self.latest.fetch_max(timestamp, Ordering::AcqRel);
+    if timestamp > previous {
+      self.moved.notify_waiters();
+    }
+    Duration::from_nanos(previous.max(timestamp))
+  }
+}
+
+impl ClockSource for HistoricalClock {
+  fn now(&self) -> Duration { Duration::from_nanos(self.latest.load(Ordering::Acquire)) }
+
+  fn sleep_until(&self, deadline: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+    Box::pin(wait_for_move(self, &self.moved, deadline))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_simulated_clocks() {
+    let step = StepClock::new(Duration::from_secs(12));
+    assert_eq!(step.now(), Duration::ZERO);
+    assert_eq!(step.tick(), 1);
+    step.tick();
+    assert_eq!(step.now(), Duration::from_secs(24));
+
+    let manual = ManualClock::new(Duration::from_secs(5));
+    manual.advance(Duration::from_secs(1));
+    assert_eq!(manual.now(), Duration::from_secs(6));
+    manual.set(Duration::from_secs(2));
+    assert_eq!(manual.now(), Duration::from_secs(2));
+
+    let historical = HistoricalClock::new();
+    assert_eq!(historical.observe(Duration::from_secs(100)), Duration::from_secs(100));
+    assert_eq!(historical.observe(Duration::from_secs(90)), Duration::from_secs(100));
+    assert_eq!(historical.now(), Duration::from_secs(100));
+  }
+
+  #[tokio::test]
+  async fn test_sleeping_follows_the_clock() {
+    let clock = std::sync::Arc::new(ManualClock::new(Duration::from_secs(10)));
+    let sleeper = tokio::spawn({
+      let clock = clock.clone();
+      async move { clock.sleep_until(Duration::from_secs(12)).await }
+    });
+    tokio::task::yield_now().await;
+    clock.advance(Duration::from_secs(1));
+    tokio::task::yield_now().await;
+    assert!(!sleeper.is_finished());
+
+    clock.advance(Duration::from_secs(1));
+    tokio::time::timeout(Duration::from_secs(1), sleeper).await.unwrap().unwrap();
+    // A deadline that has already passed does not wait.
+    clock.sleep_until(Duration::from_secs(5)).await;
+  }
+}
@@ -2,7 +2,11 @@ use std::{
   any::{Any, TypeId},
   fmt::Debug,
   ops::Deref,
-  sync::Arc,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+  time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
@@ -23,24 +27,101 @@ impl Payload for Arc<dyn Message> {}
 impl Payload for Vec<u8> {}
 
 pub struct Envelope<N: Network> {
-  pub payload: N::Payload,
-  pub type_id: TypeId,
+  pub payload:   N::Payload,
+  pub type_id:   TypeId,
+  /// The address of the connection that sent the envelope, when the transport knows it.
+  pub from:      Option<N::Address>,
+  /// Who the envelope is meant for.
+  pub to:        Delivery<N::Address>,
+  /// A signature by the sender over the payload, when the sender signs its envelopes.
+  pub signature: Option<Signature>,
+}
+
+/// Who an [`Envelope`] is meant for.
+///
+/// Transports that can route by address deliver only to the addressed connections, and agents drop
+/// envelopes that are not meant for them either way. Byte transports carry the delivery mode in
+/// each envelope's [`frame`](crate::network::frame) header but deliver to every connection, leaving
+/// the filtering to agents.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Delivery<A> {
+  /// Every connection on the network.
+  Broadcast,
+  /// Only the connection bound to the address.
+  Unicast(A),
+  /// Only the connections bound to the addresses.
+  Multicast(Vec<A>),
+}
+
+impl<A: PartialEq> Delivery<A> {
+  /// Whether the connection bound to `address` is meant to receive the envelope.
+  pub fn includes(&self, address: &A) -> bool {
+    match self {
+      Self::Broadcast => true,
+      Self::Unicast(to) => to == address,
+      Self::Multicast(to) => to.contains(address),
+    }
+  }
+}
+
+/// An ed25519 signature carried by an [`Envelope`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature(pub [u8; 64]);
+
+impl Debug for Signature {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Signature(")?;
+    for byte in &self.0[..8] {
+      write!(f, "{byte:02x}")?;
+    }
+    write!(f, "..)")
+  }
 }
 
 impl<N: Network> Debug for Envelope<N> {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "Envelope {{ payload: {:?}, type_id: {:?} }}", self.payload, self.type_id)
+    write!(
+      f,
+      "Envelope {{ payload: {:?}, type_id: {:?}, from: {:?}, to: {:?}, signature: {:?} }}",
+      self.payload, self.type_id, self.from, self.to, self.signature
+    )
   }
 }
 
 impl<N: Network> Clone for Envelope<N> {
-  fn clone(&self) -> Self { Self { payload: self.payload.clone(), type_id: self.type_id } }
+  fn clone(&self) -> Self {
+    Self {
+      payload:   self.payload.clone(),
+      type_id:   self.type_id,
+      from:      self.from,
+      to:        self.to.clone(),
+      signature: self.signature,
+    }
+  }
 }
 
 impl<N: Network> Envelope<N> {
+  pub const fn new(payload: N::Payload, type_id: TypeId) -> Self {
+    Self { payload, type_id, from: None, to: Delivery::Broadcast, signature: None }
+  }
+
+  /// Addresses the envelope to `to`.
+  pub fn with_delivery(mut self, to: Delivery<N::Address>) -> Self {
+    self.to = to;
+    self
+  }
+
+  /// Moves the envelope onto another network with the same addresses, converting its payload
+  /// with `f` and keeping everything else.
+  pub fn map_payload<M>(self, f: impl FnOnce(N::Payload) -> M::Payload) -> Envelope<M>
+  where M: Network<Address = N::Address> {
+    let Self { payload, type_id, from, to, signature } = self;
+    Envelope { payload: f(payload), type_id, from, to, signature }
+  }
+
   pub fn package<M: Message>(message: M) -> Self
   where N::Payload: Package<M> {
-    Self { payload: N::Payload::package(message), type_id: TypeId::of::<M>() }
+    Self::new(N::Payload::package(message), TypeId::of::<M>())
   }
 
   pub fn unpackage<M: Message>(&self) -> Option<impl Deref<Target = M> + '_>
@@ -83,7 +164,11 @@ where M: Message + for<'de> Deserialize<'de>
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum HandleResult<M: Message> {
+  /// Sends the message to everyone.
   Message(M),
+  /// Sends the message only to whoever sent the message being handled, or to everyone when the
+  /// sender is not known.
+  Reply(M),
   None,
   Stop,
 }
@@ -105,6 +190,74 @@ pub trait Handler<M> {
 pub type MessageHandlerFn<C: Network> =
   Box<dyn Fn(&mut dyn Any, C::Payload) -> HandleResult<Envelope<C>> + Send + Sync>;
 
+/// A snapshot of one of an agent's handlers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HandlerInfo {
+  /// The [`std::any::type_name`] of the message the handler accepts.
+  pub message_type: &'static str,
+  /// How many messages the handler has been called with.
+  pub handled:      u64,
+  /// How many of those calls replied with a message.
+  pub replies:      u64,
+  /// How many of those calls panicked and were caught by the agent's [`PanicPolicy`].
+  ///
+  /// [`PanicPolicy`]: crate::agent::PanicPolicy
+  pub panics:       u64,
+  /// The total time spent inside the handler.
+  pub busy:         Duration,
+}
+
+/// The counts an agent keeps for one of its handlers, shared with its [`ProcessingAgent`].
+///
+/// [`ProcessingAgent`]: crate::agent::ProcessingAgent
+#[derive(Debug, Default)]
+pub(crate) struct HandlerCounters {
+  pub(crate) handled:    AtomicU64,
+  pub(crate) replies:    AtomicU64,
+  pub(crate) panics:     AtomicU64,
+  pub(crate) busy_nanos: AtomicU64,
+}
+
+impl HandlerCounters {
+  pub(crate) fn record_busy(&self, busy: Duration) {
+    let nanos = u64::try_from(busy.as_nanos()).unwrap_or(u64::MAX);
+    self.busy_nanos.fetch_add(nanos, Ordering::Relaxed);
+  }
+
+  pub(crate) fn info(&self, message_type: &'static str) -> HandlerInfo {
+    HandlerInfo {
+      message_type,
+      handled: self.handled.load(Ordering::Relaxed),
+      replies: self.replies.load(Ordering::Relaxed),
+      panics: self.panics.load(Ordering::Relaxed),
+      busy: Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed)),
+    }
+  }
+}
+
+/// A handler as stored on an agent, along with what it reports through [`HandlerInfo`].
+pub(crate) struct RegisteredHandler<N: Network> {
+  pub(crate) message_type: &'static str,
+  pub(crate) counters:     Arc<HandlerCounters>,
+  pub(crate) handle:       MessageHandlerFn<N>,
+}
+
+impl<N: Network> RegisteredHandler<N> {
+  pub(crate) fn new<M, L>() -> Self
+  where
+    L: Handler<M> + 'static,
+    M: Message,
+    N::Payload: Unpacackage<M> + Package<L::Reply>, {
+    Self {
+      message_type: std::any::type_name::<M>(),
+      counters:     Arc::default(),
+      handle:       create_handler::<M, L, N>(),
+    }
+  }
+
+  pub(crate) fn info(&self) -> HandlerInfo { self.counters.info(self.message_type) }
+}
+
 // TODO: This panic is bad.
 pub fn create_handler<M, L, N>() -> MessageHandlerFn<N>
 where
@@ -127,6 +280,7 @@ where
             let reply = typed_agent.handle(&*unpacked_message).into();
             match reply {
               HandleResult::Message(message) => HandleResult::Message(Envelope::package(message)),
+              HandleResult::Reply(message) => HandleResult::Reply(Envelope::package(message)),
               HandleResult::None => HandleResult::None,
               HandleResult::Stop => HandleResult::Stop,
             }
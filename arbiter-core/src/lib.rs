@@ -1,10 +1,13 @@
 pub mod agent;
+pub mod clock;
 pub mod handler;
 pub mod network;
+pub mod rate_limit;
+#[cfg(feature = "signing")] pub mod signing;
 
 pub mod prelude {
   pub use crate::{
-    agent::LifeCycle,
+    agent::{LifeCycle, ShutdownReason},
     handler::{HandleResult, Handler, Message},
     network::Network,
   };
@@ -34,7 +37,7 @@ pub mod fixtures {
 
     fn on_start(&mut self) -> Self::StartMessage {}
 
-    fn on_stop(&mut self) -> Self::StopMessage {}
+    fn on_stop(&mut self, _reason: &ShutdownReason) -> Self::StopMessage {}
   }
 
   pub struct Logger {
@@ -48,7 +51,7 @@ pub mod fixtures {
 
     fn on_start(&mut self) -> Self::StartMessage {}
 
-    fn on_stop(&mut self) -> Self::StopMessage {}
+    fn on_stop(&mut self, _reason: &ShutdownReason) -> Self::StopMessage {}
   }
 
   impl Handler<NumberMessage> for Counter {
@@ -83,4 +86,77 @@ pub mod fixtures {
       );
     }
   }
+
+  /// An in-process byte network that, like a remote transport, carries nothing of an envelope but
+  /// its type tag and its [framed](crate::network::frame) payload. Decorators over byte networks
+  /// are tested on top of it.
+  #[derive(Debug)]
+  pub struct Wire {
+    sender:           tokio::sync::broadcast::Sender<(u64, Vec<u8>)>,
+    receiver:         tokio::sync::broadcast::Receiver<(u64, Vec<u8>)>,
+    pub(crate) types: Types,
+  }
+
+  type Types = std::sync::Arc<std::sync::RwLock<std::collections::HashMap<u64, std::any::TypeId>>>;
+
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+  pub struct WireAddress(u64);
+
+  impl crate::network::Generateable for WireAddress {
+    fn generate() -> Self {
+      use std::sync::atomic::{AtomicU64, Ordering};
+      static COUNTER: AtomicU64 = AtomicU64::new(1);
+      Self(COUNTER.fetch_add(1, Ordering::Relaxed))
+    }
+  }
+
+  impl std::fmt::Display for WireAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      write!(f, "wire-{:016x}", self.0)
+    }
+  }
+
+  impl crate::network::frame::FrameAddress for WireAddress {
+    fn to_wire(&self) -> u64 { self.0 }
+
+    fn from_wire(id: u64) -> Self { Self(id) }
+  }
+
+  impl Network for Wire {
+    type Address = WireAddress;
+    type Payload = Vec<u8>;
+
+    fn new() -> Self {
+      let (sender, receiver) = tokio::sync::broadcast::channel(1024);
+      Self { sender, receiver, types: std::sync::Arc::default() }
+    }
+
+    fn join(&self) -> Self {
+      Self {
+        sender:   self.sender.clone(),
+        receiver: self.sender.subscribe(),
+        types:    self.types.clone(),
+      }
+    }
+
+    async fn send(&self, envelope: crate::handler::Envelope<Self>) {
+      let tag = crate::network::type_hash(envelope.type_id);
+      self.types.write().unwrap().insert(tag, envelope.type_id);
+      let _ = self.sender.send((tag, crate::network::frame::frame(&envelope)));
+    }
+
+    async fn receive(&mut self) -> Option<crate::handler::Envelope<Self>> {
+      loop {
+        let (tag, framed) = match self.receiver.recv().await {
+          Ok(frame) => frame,
+          Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+          Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+        };
+        let Some(type_id) = crate::network::tagged_type(&self.types, tag) else { continue };
+        if let Some(envelope) = crate::network::frame::unframe(&framed, type_id) {
+          return Some(envelope);
+        }
+      }
+    }
+  }
 }
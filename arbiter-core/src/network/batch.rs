@@ -0,0 +1,394 @@
+//! A [`Network`] decorator that packs many byte envelopes into each frame.
+//!
+//! Chatty simulations sending over a remote transport pay framing and syscall costs per envelope.
+//! [`Batched`] holds outgoing envelopes back and sends each run of envelopes sent in a row with the
+//! same message type, sender, and recipients as one envelope, whose payload is the individual
+//! payloads with their lengths and signatures. Batches go out in the order they were started, so
+//! envelopes arrive in the order they were sent. Everything held back is sent once a batch reaches
+//! [`BatchConfig::max_bytes`], once [`BatchConfig::flush_interval`] has passed since the first
+//! envelope was held and the connection sends or receives, or on [`Network::flush`], which agents
+//! call when they stop.
+//!
+//! Every peer on the underlying network must be batched too, since plain envelopes on the wire are
+//! decoded as batches.
+
+use std::{any::TypeId, collections::VecDeque, fmt::Debug, sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{
+  handler::{Delivery, Envelope, Signature},
+  network::{Network, NetworkEvent, NetworkStats},
+};
+
+/// When [`Batched`] sends what it has been holding back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchConfig {
+  /// Send a batch as soon as it holds at least this many payload bytes.
+  pub max_bytes:      usize,
+  /// Send a batch at most this long after its first envelope was queued.
+  pub flush_interval: Duration,
+}
+
+impl Default for BatchConfig {
+  fn default() -> Self { Self { max_bytes: 64 * 1024, flush_interval: Duration::from_millis(5) } }
+}
+
+/// What envelopes must share to go out in the same batch: message type, sender, and recipients.
+type BatchKey<A> = (TypeId, Option<A>, Delivery<A>);
+
+#[derive(Debug)]
+struct Outbox<A> {
+  /// Batches in the order they were started. Only the last one is added to.
+  batches: VecDeque<(BatchKey<A>, Vec<u8>)>,
+  since:   Option<Instant>,
+}
+
+impl<A> Default for Outbox<A> {
+  fn default() -> Self { Self { batches: VecDeque::new(), since: None } }
+}
+
+impl<A: PartialEq> Outbox<A> {
+  /// How many envelopes the outbox is holding back.
+  fn held(&self) -> usize { self.batches.iter().map(|(_, batch)| count_entries(batch)).sum() }
+
+  /// Adds an entry to the last batch if it is for `key`, or starts a new one, returning the size of
+  /// the batch it went into.
+  fn push(&mut self, key: BatchKey<A>, payload: &[u8], signature: Option<&Signature>) -> usize {
+    self.since.get_or_insert_with(Instant::now);
+    if self.batches.back().is_none_or(|(last, _)| *last != key) {
+      self.batches.push_back((key, Vec::new()));
+    }
+    let (_, batch) = self.batches.back_mut().unwrap();
+    encode_into(batch, payload, signature);
+    batch.len()
+  }
+
+  /// Takes the oldest batch out of the outbox.
+  fn take(&mut self) -> Option<(BatchKey<A>, Vec<u8>)> {
+    let batch = self.batches.pop_front()?;
+    if self.batches.is_empty() {
+      self.since = None;
+    }
+    Some(batch)
+  }
+
+  /// Puts `batch` back ahead of everything queued since it was taken.
+  fn restore(&mut self, key: BatchKey<A>, mut batch: Vec<u8>) {
+    match self.batches.front_mut() {
+      Some((first, newer)) if *first == key => {
+        batch.append(newer);
+        *newer = batch;
+      },
+      _ => self.batches.push_front((key, batch)),
+    }
+    self.since.get_or_insert_with(Instant::now);
+  }
+}
+
+/// A batch on its way to the inner network, put back into the outbox if its send is cancelled
+/// before it completes, so that a flush interrupted by `select!` loses nothing. A batch cancelled
+/// part-way through its send may go out twice.
+struct InFlight<'a, A: PartialEq> {
+  outbox: &'a Mutex<Outbox<A>>,
+  batch:  Option<(BatchKey<A>, Vec<u8>)>,
+}
+
+impl<A: PartialEq> Drop for InFlight<'_, A> {
+  fn drop(&mut self) {
+    if let (Some((key, batch)), Ok(mut outbox)) = (self.batch.take(), self.outbox.lock()) {
+      outbox.restore(key, batch);
+    }
+  }
+}
+
+pub struct Batched<N: Network<Payload = Vec<u8>>> {
+  inner:   N,
+  config:  BatchConfig,
+  address: Option<N::Address>,
+  outbox:  Mutex<Outbox<N::Address>>,
+  inbox:   VecDeque<Envelope<Self>>,
+}
+
+impl<N: Network<Payload = Vec<u8>> + Debug> Debug for Batched<N> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Batched")
+      .field("inner", &self.inner)
+      .field("config", &self.config)
+      .field("address", &self.address)
+      .finish_non_exhaustive()
+  }
+}
+
+impl<N: Network<Payload = Vec<u8>>> Batched<N> {
+  /// Wraps `inner`, batching with `config` on this network and every network joined from it.
+  pub fn with_config(inner: N, config: BatchConfig) -> Self {
+    Self {
+      inner,
+      config,
+      address: None,
+      outbox: Mutex::new(Outbox::default()),
+      inbox: VecDeque::new(),
+    }
+  }
+
+  pub const fn config(&self) -> BatchConfig { self.config }
+
+  pub const fn inner(&self) -> &N { &self.inner }
+
+  /// Sends every batch that is being held back, one at a time.
+  async fn flush_batches(&self) {
+    loop {
+      let Some(batch) = self.outbox.lock().unwrap().take() else { return };
+      self.send_batch(batch).await;
+    }
+  }
+
+  async fn send_batch(&self, batch: (BatchKey<N::Address>, Vec<u8>)) {
+    let ((type_id, from, to), payload) = batch.clone();
+    let mut in_flight = InFlight { outbox: &self.outbox, batch: Some(batch) };
+    let mut envelope = Envelope::new(payload, type_id).with_delivery(to);
+    envelope.from = from;
+    self.inner.send(envelope).await;
+    in_flight.batch = None;
+  }
+
+  fn deadline(&self) -> Option<Instant> {
+    self.outbox.lock().unwrap().since.map(|since| since + self.config.flush_interval)
+  }
+
+  fn unpack(&mut self, envelope: Envelope<N>) {
+    let Some(entries) = decode(&envelope.payload) else {
+      tracing::debug!("dropping malformed batch of {} bytes", envelope.payload.len());
+      return;
+    };
+    self.inbox.extend(entries.into_iter().map(|(signature, payload)| {
+      let mut unpacked =
+        Envelope::new(payload, envelope.type_id).with_delivery(envelope.to.clone());
+      unpacked.from = envelope.from;
+      unpacked.signature = signature;
+      unpacked
+    }));
+  }
+}
+
+impl<N: Network<Payload = Vec<u8>>> Drop for Batched<N> {
+  fn drop(&mut self) {
+    let held = self.outbox.get_mut().map_or(0, |outbox| outbox.batches.len());
+    if held > 0 {
+      tracing::warn!("dropping a batched connection with {held} unflushed batches");
+    }
+  }
+}
+
+/// Appends one entry to `batch`: the payload's length, whether a signature follows, the signature
+/// if there is one, and the payload.
+fn encode_into(batch: &mut Vec<u8>, payload: &[u8], signature: Option<&Signature>) {
+  let len = u32::try_from(payload.len()).expect("payloads in a batch are limited to 4 GiB");
+  batch.extend_from_slice(&len.to_be_bytes());
+  match signature {
+    Some(signature) => {
+      batch.push(1);
+      batch.extend_from_slice(&signature.0);
+    },
+    None => batch.push(0),
+  }
+  batch.extend_from_slice(payload);
+}
+
+/// How many entries `batch` holds, read from their lengths without copying them out.
+fn count_entries(mut batch: &[u8]) -> usize {
+  let mut count = 0;
+  while let Some((len, rest)) = batch.split_first_chunk::<4>() {
+    let signature = if rest.first() == Some(&1) { 64 } else { 0 };
+    let skip = 1 + signature + u32::from_be_bytes(*len) as usize;
+    batch = rest.get(skip..).unwrap_or_default();
+    count += 1;
+  }
+  count
+}
+
+fn decode(mut batch: &[u8]) -> Option<Vec<(Option<Signature>, Vec<u8>)>> {
+  let mut entries = Vec::new();
+  while !batch.is_empty() {
+    let (len, rest) = batch.split_at_checked(4)?;
+    let len = u32::from_be_bytes(len.try_into().ok()?) as usize;
+    let (signed, mut rest) = rest.split_first()?;
+    let signature = match signed {
+      0 => None,
+      1 => {
+        let (signature, after) = rest.split_at_checked(64)?;
+        rest = after;
+        Some(Signature(signature.try_into().ok()?))
+      },
+      _ => return None,
+    };
+    let (payload, after) = rest.split_at_checked(len)?;
+    entries.push((signature, payload.to_vec()));
+    batch = after;
+  }
+  Some(entries)
+}
+
+impl<N: Network<Payload = Vec<u8>>> Network for Batched<N> {
+  type Address = N::Address;
+  type Payload = Vec<u8>;
+
+  fn new() -> Self { Self::with_config(N::new(), BatchConfig::default()) }
+
+  fn join(&self) -> Self { Self::with_config(self.inner.join(), self.config) }
+
+  fn bind(&mut self, address: Self::Address) {
+    self.address = Some(address);
+    self.inner.bind(address);
+  }
+
+  async fn send(&self, envelope: Envelope<Self>) {
+    let key = (envelope.type_id, envelope.from.or(self.address), envelope.to.clone());
+    let size =
+      self.outbox.lock().unwrap().push(key, &envelope.payload, envelope.signature.as_ref());
+    // Connections that rarely receive still send what they have held back for too long. Batches
+    // ahead of a full one go out with it, to keep them in order.
+    if size >= self.config.max_bytes
+      || self.deadline().is_some_and(|deadline| deadline <= Instant::now())
+    {
+      self.flush_batches().await;
+    }
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    loop {
+      if let Some(envelope) = self.inbox.pop_front() {
+        return Some(envelope);
+      }
+      let deadline = self.deadline();
+      let flush_at = deadline.unwrap_or_else(Instant::now);
+      tokio::select! {
+        envelope = self.inner.receive() => self.unpack(envelope?),
+        () = tokio::time::sleep_until(flush_at), if deadline.is_some() => {
+          self.flush_batches().await;
+        },
+      }
+    }
+  }
+
+  fn take_events(&mut self) -> Vec<NetworkEvent> { self.inner.take_events() }
+
+  fn event_signal(&self) -> Option<std::sync::Arc<tokio::sync::Notify>> {
+    self.inner.event_signal()
+  }
+
+  /// Envelopes held back for a batch, and envelopes unpacked from a batch but not yet received,
+  /// count towards the queue depth.
+  fn stats(&self) -> NetworkStats {
+    let inner = self.inner.stats();
+    let held = self.outbox.lock().unwrap().held();
+    NetworkStats { queue_depth: inner.queue_depth + held + self.inbox.len(), ..inner }
+  }
+
+  /// Sends every batch that is being held back.
+  async fn flush(&self) {
+    self.flush_batches().await;
+    self.inner.flush().await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    agent::Agent,
+    fixtures::{Logger, NumberMessage, TextMessage, Wire},
+  };
+
+  #[test]
+  fn test_batch_round_trip() {
+    let signature = Signature([7; 64]);
+    let entries =
+      [(None, b"first".to_vec()), (Some(signature), Vec::new()), (None, b"third".to_vec())];
+    let mut batch = Vec::new();
+    for (signature, payload) in &entries {
+      encode_into(&mut batch, payload, signature.as_ref());
+    }
+    assert_eq!(decode(&batch).unwrap(), entries);
+    assert_eq!(count_entries(&batch), entries.len());
+    assert!(decode(&batch[..batch.len() - 1]).is_none());
+  }
+
+  #[tokio::test]
+  async fn test_batches_are_held_until_flushed() {
+    let config = BatchConfig { max_bytes: 1024, flush_interval: Duration::from_secs(60) };
+    let sender = Batched::with_config(Wire::new(), config);
+    let mut receiver = sender.join();
+    let type_id = TypeId::of::<TextMessage>();
+    for payload in [b"first".to_vec(), b"second".to_vec()] {
+      sender.send(Envelope::new(payload, type_id)).await;
+    }
+    let early = tokio::time::timeout(Duration::from_millis(20), receiver.receive()).await;
+    assert!(early.is_err());
+    assert_eq!(sender.stats().queue_depth, 2);
+
+    sender.flush().await;
+    assert_eq!(sender.stats().queue_depth, 0);
+    for payload in [b"first".to_vec(), b"second".to_vec()] {
+      let envelope = receiver.receive().await.unwrap();
+      assert_eq!((envelope.type_id, envelope.payload), (type_id, payload));
+    }
+  }
+
+  #[tokio::test]
+  async fn test_batches_keep_the_order_envelopes_were_sent_in() {
+    let config = BatchConfig { max_bytes: 1024, flush_interval: Duration::from_secs(60) };
+    let sender = Batched::with_config(Wire::new(), config);
+    let mut receiver = sender.join();
+    let (text, number) = (TypeId::of::<TextMessage>(), TypeId::of::<NumberMessage>());
+    let sent = [(text, b"a".to_vec()), (number, b"b".to_vec()), (text, b"c".to_vec())];
+    for (type_id, payload) in sent.clone() {
+      sender.send(Envelope::new(payload, type_id)).await;
+    }
+    sender.flush().await;
+
+    for (type_id, payload) in sent {
+      let envelope = receiver.receive().await.unwrap();
+      assert_eq!((envelope.type_id, envelope.payload), (type_id, payload));
+    }
+  }
+
+  #[tokio::test]
+  async fn test_sending_flushes_overdue_batches() {
+    let config = BatchConfig { max_bytes: 1024, flush_interval: Duration::from_millis(5) };
+    let sender = Batched::with_config(Wire::new(), config);
+    let mut receiver = sender.join();
+    let type_id = TypeId::of::<TextMessage>();
+    sender.send(Envelope::new(b"first".to_vec(), type_id)).await;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    sender.send(Envelope::new(b"second".to_vec(), type_id)).await;
+
+    // The sender never receives, but both envelopes go out.
+    for payload in [b"first".to_vec(), b"second".to_vec()] {
+      let envelope = tokio::time::timeout(Duration::from_secs(1), receiver.receive()).await;
+      assert_eq!(envelope.unwrap().unwrap().payload, payload);
+    }
+  }
+
+  #[tokio::test]
+  async fn test_stopping_agents_flush_their_batches() {
+    let config = BatchConfig { max_bytes: 1024, flush_interval: Duration::from_secs(60) };
+    let network = Batched::with_config(Wire::new(), config);
+    let mut listener = network.join();
+    let agent = Agent::<Logger, _>::new_join_network(
+      Logger { name: "TestLogger".to_string(), message_count: 0 },
+      &network,
+    );
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    processing_agent.stop().await;
+    processing_agent.join().await;
+    // The start and stop messages.
+    for _ in 0..2 {
+      let envelope = tokio::time::timeout(Duration::from_secs(1), listener.receive()).await;
+      assert_eq!(envelope.unwrap().unwrap().type_id, TypeId::of::<()>());
+    }
+  }
+}
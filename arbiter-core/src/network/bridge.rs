@@ -0,0 +1,244 @@
+//! Relaying envelopes between two different networks.
+//!
+//! A [`NetworkBridge`] joins one network of each kind and copies envelopes across. Only message
+//! types registered with [`NetworkBridge::relay`], [`NetworkBridge::forward`], or
+//! [`NetworkBridge::backward`] are relayed. Each of these unpacks the message from one network's
+//! payload format and packs it into the other's, so an [`InMemory`](super::memory::InMemory)
+//! simulation can exchange messages with a byte-payload transport as long as the relayed types are
+//! serializable.
+//!
+//! Relayed envelopes are sent with the bridge's own address as [`Envelope::from`], and the bridge
+//! ignores envelopes from itself, so traffic does not echo back across. On transports that do not
+//! carry the sender address, only relay each type in one direction.
+//!
+//! Only envelopes broadcast or addressed to the bridge are relayed, so unicasts between peers on
+//! transports that hand every connection everything stay on their own network. Addresses on one
+//! network mean nothing on the other, so relayed envelopes are always broadcast.
+
+use std::{any::TypeId, collections::HashMap};
+
+use crate::{
+  handler::{Envelope, Message, Package, Unpacackage},
+  network::{Generateable, Network},
+};
+
+/// One of the two networks a [`NetworkBridge`] joins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+  /// The first network passed to [`NetworkBridge::new`].
+  A,
+  /// The second network passed to [`NetworkBridge::new`].
+  B,
+}
+
+type Translate<From, To> = Box<dyn Fn(&Envelope<From>) -> Option<Envelope<To>> + Send + Sync>;
+
+fn translate<M, From, To>() -> Translate<From, To>
+where
+  M: Message + Clone,
+  From: Network,
+  To: Network,
+  From::Payload: Unpacackage<M>,
+  To::Payload: Package<M>, {
+  Box::new(|envelope| envelope.unpackage::<M>().map(|message| Envelope::package(message.clone())))
+}
+
+pub struct NetworkBridge<A: Network, B: Network> {
+  a:         A,
+  b:         B,
+  a_address: A::Address,
+  b_address: B::Address,
+  a_to_b:    HashMap<TypeId, Translate<A, B>>,
+  b_to_a:    HashMap<TypeId, Translate<B, A>>,
+}
+
+impl<A: Network, B: Network> NetworkBridge<A, B> {
+  /// Joins `a` and `b` under freshly generated addresses.
+  pub fn new(a: &A, b: &B) -> Self {
+    let (a_address, b_address) = (A::Address::generate(), B::Address::generate());
+    let (mut a, mut b) = (a.join(), b.join());
+    a.bind(a_address);
+    b.bind(b_address);
+    Self { a, b, a_address, b_address, a_to_b: HashMap::new(), b_to_a: HashMap::new() }
+  }
+
+  /// The bridge's address on the first network.
+  pub const fn a_address(&self) -> A::Address { self.a_address }
+
+  /// The bridge's address on the second network.
+  pub const fn b_address(&self) -> B::Address { self.b_address }
+
+  /// Relays messages of type `M` from the first network to the second.
+  pub fn forward<M>(mut self) -> Self
+  where
+    M: Message + Clone,
+    A::Payload: Unpacackage<M>,
+    B::Payload: Package<M>, {
+    self.a_to_b.insert(TypeId::of::<M>(), translate::<M, A, B>());
+    self
+  }
+
+  /// Relays messages of type `M` from the second network to the first.
+  pub fn backward<M>(mut self) -> Self
+  where
+    M: Message + Clone,
+    B::Payload: Unpacackage<M>,
+    A::Payload: Package<M>, {
+    self.b_to_a.insert(TypeId::of::<M>(), translate::<M, B, A>());
+    self
+  }
+
+  /// Relays messages of type `M` in both directions.
+  pub fn relay<M>(self) -> Self
+  where
+    M: Message + Clone,
+    A::Payload: Unpacackage<M> + Package<M>,
+    B::Payload: Unpacackage<M> + Package<M>, {
+    self.forward::<M>().backward::<M>()
+  }
+
+  /// Relays envelopes until both networks have stopped producing them, and returns the side that
+  /// stopped first. Once one side stops, envelopes from the other are still relayed to it.
+  pub async fn run(mut self) -> Side {
+    let mut stopped = None;
+    loop {
+      tokio::select! {
+        envelope = self.a.receive(), if stopped != Some(Side::A) => {
+          let Some(envelope) = envelope else {
+            match stopped {
+              Some(_) => return Side::B,
+              None => {
+                tracing::warn!("bridged network A stopped producing envelopes");
+                stopped = Some(Side::A);
+                continue;
+              },
+            }
+          };
+          if envelope.from == Some(self.a_address) || !envelope.to.includes(&self.a_address) {
+            continue;
+          }
+          if let Some(mut relayed) = self.a_to_b.get(&envelope.type_id).and_then(|t| t(&envelope)) {
+            relayed.from = Some(self.b_address);
+            self.b.send(relayed).await;
+          }
+        },
+        envelope = self.b.receive(), if stopped != Some(Side::B) => {
+          let Some(envelope) = envelope else {
+            match stopped {
+              Some(_) => return Side::A,
+              None => {
+                tracing::warn!("bridged network B stopped producing envelopes");
+                stopped = Some(Side::B);
+                continue;
+              },
+            }
+          };
+          if envelope.from == Some(self.b_address) || !envelope.to.includes(&self.b_address) {
+            continue;
+          }
+          if let Some(mut relayed) = self.b_to_a.get(&envelope.type_id).and_then(|t| t(&envelope)) {
+            relayed.from = Some(self.a_address);
+            self.a.send(relayed).await;
+          }
+        },
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+  };
+
+  use super::*;
+  use crate::{
+    fixtures::{NumberMessage, Wire, WireAddress},
+    handler::Delivery,
+    network::memory::{InMemory, InMemoryAddress},
+  };
+
+  /// A network that never produces anything and records what is sent to it.
+  #[derive(Debug, Default)]
+  struct Closed {
+    sent: Arc<Mutex<Vec<TypeId>>>,
+  }
+
+  impl Network for Closed {
+    type Address = InMemoryAddress;
+    type Payload = Arc<dyn Message>;
+
+    fn new() -> Self { Self::default() }
+
+    fn join(&self) -> Self { Self { sent: self.sent.clone() } }
+
+    async fn send(&self, envelope: Envelope<Self>) {
+      self.sent.lock().unwrap().push(envelope.type_id);
+    }
+
+    async fn receive(&mut self) -> Option<Envelope<Self>> { None }
+  }
+
+  #[tokio::test]
+  async fn test_bridge_relays_without_echo() {
+    let (left, right) = (InMemory::new(), InMemory::new());
+    let bridge = NetworkBridge::new(&left, &right).relay::<NumberMessage>();
+    let mut left_listener = left.join();
+    let mut right_listener = right.join();
+    let relay = tokio::spawn(bridge.run());
+
+    left.send(Envelope::package(NumberMessage { value: 7 })).await;
+    let received = right_listener.receive().await.unwrap();
+    assert_eq!(received.unpackage::<NumberMessage>().unwrap().value, 7);
+
+    // The left side only ever sees the original envelope.
+    let original = left_listener.receive().await.unwrap();
+    assert_eq!(original.unpackage::<NumberMessage>().unwrap().value, 7);
+    let echo = tokio::time::timeout(Duration::from_millis(20), left_listener.receive()).await;
+    assert!(echo.is_err());
+
+    relay.abort();
+  }
+
+  #[tokio::test]
+  async fn test_bridge_relays_only_what_is_meant_for_it() {
+    let (wire, memory) = (Wire::new(), InMemory::new());
+    let bridge = NetworkBridge::new(&wire, &memory).relay::<u32>();
+    let address = bridge.a_address();
+    let mut listener = memory.join();
+    let relay = tokio::spawn(bridge.run());
+
+    // Only the unicast to the bridge and the broadcast cross over.
+    let sender = wire.join();
+    for to in [Delivery::Unicast(WireAddress::generate()), Delivery::Unicast(address)] {
+      sender.send(Envelope::package(1_u32).with_delivery(to)).await;
+    }
+    sender.send(Envelope::package(2_u32)).await;
+
+    for value in [1, 2] {
+      let received = listener.receive().await.unwrap();
+      let relayed = (*received.unpackage::<u32>().unwrap(), received.to.clone());
+      assert_eq!(relayed, (value, Delivery::Broadcast));
+    }
+    let stray = tokio::time::timeout(Duration::from_millis(20), listener.receive()).await;
+    assert!(stray.is_err());
+
+    relay.abort();
+  }
+
+  #[tokio::test]
+  async fn test_bridge_outlives_a_stopped_network() {
+    let (closed, open) = (Closed::new(), InMemory::new());
+    let bridge = NetworkBridge::new(&closed, &open).relay::<NumberMessage>();
+    let relay = tokio::spawn(bridge.run());
+
+    open.send(Envelope::package(NumberMessage { value: 7 })).await;
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(*closed.sent.lock().unwrap(), [TypeId::of::<NumberMessage>()]);
+    assert!(!relay.is_finished());
+
+    relay.abort();
+  }
+}
@@ -0,0 +1,178 @@
+//! A [`Network`] decorator that compresses large byte payloads with LZ4.
+//!
+//! [`Compressed`] sits between packaged payloads and the transport. Payloads of at least
+//! [`Compressed::threshold`] bytes are compressed before they are sent, and every payload is
+//! prefixed with a byte saying whether it was, so small messages cost one extra byte and are never
+//! slowed down. Payloads that do not shrink are sent as they are.
+//!
+//! A compressed payload states its decompressed size up front. Payloads claiming more than
+//! [`Compressed::max_size`] are dropped before anything is allocated for them, so a peer cannot
+//! make a receiver allocate arbitrarily large buffers.
+//!
+//! LZ4 is the only codec for now. The header byte leaves room for others, such as zstd for better
+//! ratios on slow links.
+//!
+//! Every peer on the underlying network must be compressed too, since plain payloads on the wire
+//! are read as having a header.
+
+use std::fmt::Debug;
+
+use crate::{
+  handler::Envelope,
+  network::{Network, NetworkEvent, NetworkStats},
+};
+
+/// The threshold used by [`Network::new`].
+pub const DEFAULT_THRESHOLD: usize = 1024;
+
+/// The largest decompressed payload accepted by default.
+pub const DEFAULT_MAX_SIZE: usize = 64 * 1024 * 1024;
+
+const RAW: u8 = 0;
+const LZ4: u8 = 1;
+
+pub struct Compressed<N: Network<Payload = Vec<u8>>> {
+  inner:     N,
+  threshold: usize,
+  max_size:  usize,
+}
+
+impl<N: Network<Payload = Vec<u8>> + Debug> Debug for Compressed<N> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Compressed")
+      .field("inner", &self.inner)
+      .field("threshold", &self.threshold)
+      .field("max_size", &self.max_size)
+      .finish()
+  }
+}
+
+impl<N: Network<Payload = Vec<u8>>> Compressed<N> {
+  /// Wraps `inner`, compressing payloads of at least `threshold` bytes on this network and every
+  /// network joined from it.
+  pub const fn with_threshold(inner: N, threshold: usize) -> Self {
+    Self { inner, threshold, max_size: DEFAULT_MAX_SIZE }
+  }
+
+  /// Drops received payloads that would decompress to more than `max_size` bytes.
+  pub const fn with_max_size(mut self, max_size: usize) -> Self {
+    self.max_size = max_size;
+    self
+  }
+
+  pub const fn threshold(&self) -> usize { self.threshold }
+
+  pub const fn max_size(&self) -> usize { self.max_size }
+
+  pub const fn inner(&self) -> &N { &self.inner }
+}
+
+fn compress(payload: Vec<u8>, threshold: usize) -> Vec<u8> {
+  if payload.len() >= threshold {
+    let compressed = lz4_flex::compress_prepend_size(&payload);
+    if compressed.len() < payload.len() {
+      let mut framed = Vec::with_capacity(compressed.len() + 1);
+      framed.push(LZ4);
+      framed.extend_from_slice(&compressed);
+      return framed;
+    }
+  }
+  let mut framed = Vec::with_capacity(payload.len() + 1);
+  framed.push(RAW);
+  framed.extend_from_slice(&payload);
+  framed
+}
+
+fn decompress(framed: &[u8], max_size: usize) -> Option<Vec<u8>> {
+  match framed.split_first()? {
+    (&RAW, payload) => Some(payload.to_vec()),
+    (&LZ4, compressed) => {
+      // `compress_prepend_size` writes the decompressed size as a little-endian u32.
+      let (size, compressed) = compressed.split_at_checked(4)?;
+      let size = u32::from_le_bytes(size.try_into().ok()?) as usize;
+      if size > max_size {
+        tracing::warn!("dropping payload that claims to decompress to {size} bytes");
+        return None;
+      }
+      let mut payload = vec![0; size];
+      let written = lz4_flex::decompress_into(compressed, &mut payload).ok()?;
+      (written == size).then_some(payload)
+    },
+    _ => None,
+  }
+}
+
+impl<N: Network<Payload = Vec<u8>>> Network for Compressed<N> {
+  type Address = N::Address;
+  type Payload = Vec<u8>;
+
+  fn new() -> Self { Self::with_threshold(N::new(), DEFAULT_THRESHOLD) }
+
+  fn join(&self) -> Self {
+    Self::with_threshold(self.inner.join(), self.threshold).with_max_size(self.max_size)
+  }
+
+  fn bind(&mut self, address: Self::Address) { self.inner.bind(address); }
+
+  async fn send(&self, envelope: Envelope<Self>) {
+    let threshold = self.threshold;
+    self.inner.send(envelope.map_payload(|payload| compress(payload, threshold))).await;
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    loop {
+      let envelope = self.inner.receive().await?;
+      match decompress(&envelope.payload, self.max_size) {
+        Some(payload) => return Some(envelope.map_payload(|_| payload)),
+        None => tracing::debug!("dropping payload that failed to decompress"),
+      }
+    }
+  }
+
+  fn take_events(&mut self) -> Vec<NetworkEvent> { self.inner.take_events() }
+
+  fn event_signal(&self) -> Option<std::sync::Arc<tokio::sync::Notify>> {
+    self.inner.event_signal()
+  }
+
+  async fn flush(&self) { self.inner.flush().await; }
+
+  fn stats(&self) -> NetworkStats { self.inner.stats() }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_compression_round_trip() {
+    let large = vec![7u8; 4096];
+    let framed = compress(large.clone(), 1024);
+    assert_eq!(framed[0], LZ4);
+    assert!(framed.len() < large.len());
+    assert_eq!(decompress(&framed, DEFAULT_MAX_SIZE).unwrap(), large);
+
+    let small = b"small".to_vec();
+    let framed = compress(small.clone(), 1024);
+    assert_eq!(framed[0], RAW);
+    assert_eq!(decompress(&framed, DEFAULT_MAX_SIZE).unwrap(), small);
+  }
+
+  #[test]
+  fn test_decompression_is_bounded() {
+    let framed = compress(vec![7u8; 4096], 1024);
+    assert!(decompress(&framed, 4095).is_none());
+    assert!(decompress(&framed, 4096).is_some());
+
+    // A tiny payload claiming to decompress to 4 GiB is rejected without allocating.
+    let mut bomb = vec![LZ4];
+    bomb.extend_from_slice(&u32::MAX.to_le_bytes());
+    bomb.extend_from_slice(&[0; 8]);
+    assert!(decompress(&bomb, DEFAULT_MAX_SIZE).is_none());
+
+    // So is one that decompresses to less than it claims.
+    let mut short = framed.clone();
+    short[1..5].copy_from_slice(&4097u32.to_le_bytes());
+    assert!(decompress(&short, DEFAULT_MAX_SIZE).is_none());
+  }
+}
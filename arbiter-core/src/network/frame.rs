@@ -0,0 +1,192 @@
+//! The header byte transports send with each payload, carrying what an [`Envelope`] knows besides
+//! its type and payload.
+//!
+//! A header starts with a flags byte. If bit 0 is set, the sender's address follows as 8
+//! big-endian bytes, and if bit 1 is set, a 64-byte ed25519 [`Signature`] follows that. The
+//! recipients come last: a single address if bit 2 is set, or a big-endian `u32` count and that
+//! many addresses if bit 3 is set. With neither, the envelope is a broadcast. Other bits are
+//! reserved, and headers that set them, or both recipient bits, are rejected. Transports place the
+//! header wherever suits them: NATS and MQTT put it in front of the payload with [`frame`], ZeroMQ
+//! sends it as a frame of its own, and gRPC as a field of its frame.
+//!
+//! Addresses go on the wire as `u64`s, so transports using the header need addresses that convert
+//! to and from one, through [`FrameAddress`].
+
+use std::any::TypeId;
+
+use crate::{
+  handler::{Delivery, Envelope, Signature},
+  network::Network,
+};
+
+const FROM: u8 = 1;
+const SIGNATURE: u8 = 1 << 1;
+const UNICAST: u8 = 1 << 2;
+const MULTICAST: u8 = 1 << 3;
+
+/// An address that can be written into a [`Header`].
+pub trait FrameAddress: Copy {
+  fn to_wire(&self) -> u64;
+  fn from_wire(id: u64) -> Self;
+}
+
+/// Everything about an envelope that a byte transport carries besides its type and payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header<A> {
+  pub from:      Option<A>,
+  pub signature: Option<Signature>,
+  pub to:        Delivery<A>,
+}
+
+impl<A: FrameAddress> Header<A> {
+  pub fn of<N: Network<Address = A>>(envelope: &Envelope<N>) -> Self {
+    Self { from: envelope.from, signature: envelope.signature, to: envelope.to.clone() }
+  }
+
+  pub fn write(&self, out: &mut Vec<u8>) {
+    let to = match self.to {
+      Delivery::Broadcast => 0,
+      Delivery::Unicast(_) => UNICAST,
+      Delivery::Multicast(_) => MULTICAST,
+    };
+    let flags = if self.from.is_some() { FROM } else { 0 }
+      | if self.signature.is_some() { SIGNATURE } else { 0 }
+      | to;
+    out.push(flags);
+    if let Some(from) = self.from {
+      out.extend_from_slice(&from.to_wire().to_be_bytes());
+    }
+    if let Some(signature) = &self.signature {
+      out.extend_from_slice(&signature.0);
+    }
+    match &self.to {
+      Delivery::Broadcast => {},
+      Delivery::Unicast(to) => out.extend_from_slice(&to.to_wire().to_be_bytes()),
+      Delivery::Multicast(to) => {
+        let count = u32::try_from(to.len()).expect("multicasts are limited to 2^32 recipients");
+        out.extend_from_slice(&count.to_be_bytes());
+        for to in to {
+          out.extend_from_slice(&to.to_wire().to_be_bytes());
+        }
+      },
+    }
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut out = Vec::new();
+    self.write(&mut out);
+    out
+  }
+
+  /// Reads a header from the front of `bytes`, returning it along with whatever follows it.
+  pub fn read(bytes: &[u8]) -> Option<(Self, &[u8])> {
+    let (&flags, mut rest) = bytes.split_first()?;
+    if flags & !(FROM | SIGNATURE | UNICAST | MULTICAST) != 0
+      || flags & (UNICAST | MULTICAST) == UNICAST | MULTICAST
+    {
+      return None;
+    }
+    let from = if flags & FROM != 0 { Some(read_address(&mut rest)?) } else { None };
+    let mut signature = None;
+    if flags & SIGNATURE != 0 {
+      let (bytes, after) = rest.split_at_checked(64)?;
+      signature = Some(Signature(bytes.try_into().ok()?));
+      rest = after;
+    }
+    let to = if flags & UNICAST != 0 {
+      Delivery::Unicast(read_address(&mut rest)?)
+    } else if flags & MULTICAST != 0 {
+      let (count, after) = rest.split_first_chunk::<4>()?;
+      rest = after;
+      let count = u32::from_be_bytes(*count);
+      Delivery::Multicast((0..count).map(|_| read_address(&mut rest)).collect::<Option<_>>()?)
+    } else {
+      Delivery::Broadcast
+    };
+    Some((Self { from, signature, to }, rest))
+  }
+
+  /// Reads a header that makes up all of `bytes`. No bytes at all, as from peers that leave the
+  /// header out, read as a header carrying nothing.
+  pub fn decode(bytes: &[u8]) -> Option<Self> {
+    if bytes.is_empty() {
+      return Some(Self { from: None, signature: None, to: Delivery::Broadcast });
+    }
+    Self::read(bytes).and_then(|(header, rest)| rest.is_empty().then_some(header))
+  }
+
+  /// Fills in what the header carries on `envelope`.
+  pub fn apply<N: Network<Address = A>>(self, envelope: &mut Envelope<N>) {
+    envelope.from = self.from;
+    envelope.signature = self.signature;
+    envelope.to = self.to;
+  }
+}
+
+/// Reads an address from the front of `bytes` and advances past it.
+fn read_address<A: FrameAddress>(bytes: &mut &[u8]) -> Option<A> {
+  let (id, rest) = bytes.split_first_chunk::<8>()?;
+  *bytes = rest;
+  Some(A::from_wire(u64::from_be_bytes(*id)))
+}
+
+/// The envelope's header followed by its payload.
+pub fn frame<N>(envelope: &Envelope<N>) -> Vec<u8>
+where
+  N: Network<Payload = Vec<u8>>,
+  N::Address: FrameAddress, {
+  let mut framed = Header::of(envelope).encode();
+  framed.extend_from_slice(&envelope.payload);
+  framed
+}
+
+/// Rebuilds an envelope of type `type_id` from what [`frame`] produced.
+pub fn unframe<N>(framed: &[u8], type_id: TypeId) -> Option<Envelope<N>>
+where
+  N: Network<Payload = Vec<u8>>,
+  N::Address: FrameAddress, {
+  let (header, payload) = Header::read(framed)?;
+  let mut envelope = Envelope::new(payload.to_vec(), type_id);
+  header.apply(&mut envelope);
+  Some(envelope)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{Wire, WireAddress};
+
+  #[test]
+  fn test_frames_round_trip() {
+    let type_id = TypeId::of::<u8>();
+    let mut envelope = Envelope::<Wire>::new(b"payload".to_vec(), type_id);
+    let framed = frame(&envelope);
+    assert_eq!(framed[0], 0);
+    let unframed = unframe::<Wire>(&framed, type_id).unwrap();
+    assert_eq!(
+      (unframed.from, unframed.signature, unframed.payload),
+      (None, None, envelope.payload.clone())
+    );
+
+    envelope.from = Some(WireAddress::from_wire(7));
+    envelope.signature = Some(Signature([3; 64]));
+    for to in [
+      Delivery::Unicast(WireAddress::from_wire(8)),
+      Delivery::Multicast(vec![WireAddress::from_wire(8), WireAddress::from_wire(9)]),
+    ] {
+      envelope.to = to;
+      let unframed = unframe::<Wire>(&frame(&envelope), type_id).unwrap();
+      assert_eq!(Header::of(&unframed), Header::of(&envelope));
+      assert_eq!(unframed.payload, envelope.payload);
+    }
+    let framed = frame(&envelope);
+
+    // Truncated headers and reserved flags are rejected.
+    assert!(unframe::<Wire>(&framed[..40], type_id).is_none());
+    assert!(unframe::<Wire>(&[0x80], type_id).is_none());
+    assert!(unframe::<Wire>(&[UNICAST | MULTICAST, 0, 0, 0, 0], type_id).is_none());
+    assert!(Header::<WireAddress>::decode(&framed).is_none());
+    let empty = Header { from: None, signature: None, to: Delivery::Broadcast };
+    assert_eq!(Header::<WireAddress>::decode(&[]), Some(empty));
+  }
+}
@@ -0,0 +1,457 @@
+//! A [`Network`] carried over gRPC bidirectional streams.
+//!
+//! Every connection opens one `Relay.Stream` call to a relay server, which forwards each frame it
+//! receives to every open stream. [`serve`] runs such a relay; since the frame format is defined in
+//! `proto/arbiter.proto`, services written in other languages can connect to it as peers.
+//!
+//! Frames carry the message's [tag](super::tags), so peers must register the same tags or be built
+//! from the same source, and a connection can only hand back envelopes for registered types and
+//! types it has sent or [`Grpc::register`]ed. Registered tags are also what peers in other
+//! languages compute, as the FNV-1a hash of the registered name. The sender, signature and
+//! recipients travel in the frame's [header](super::frame).
+//!
+//! When the stream fails, receive reopens it after a [`Backoff`], and frames sent while the stream
+//! is down or being opened wait in a bounded [`ResendQueue`] until it is back, along with any the
+//! failed stream had been handed but never took. Frames the stream took before failing may still
+//! be lost, since the relay does not acknowledge them. Each loss and recovery is reported as a
+//! [`NetworkEvent`] as soon as it happens.
+
+use std::{
+  any::TypeId,
+  collections::HashMap,
+  net::SocketAddr,
+  pin::Pin,
+  sync::{Arc, Mutex, RwLock},
+  task::{Context, Poll},
+};
+
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::{
+  handler::{Envelope, Message},
+  network::{
+    frame::{FrameAddress, Header},
+    reconnect::{Backoff, ResendQueue},
+    tagged_type, type_hash, EventLog, Generateable, Network, NetworkEvent, NetworkStats,
+  },
+};
+
+pub mod proto {
+  tonic::include_proto!("arbiter.v1");
+}
+
+use proto::{
+  relay_client::RelayClient,
+  relay_server::{Relay, RelayServer},
+  EnvelopeFrame,
+};
+
+/// The relay used by [`Network::new`] when `ARBITER_GRPC_ENDPOINT` is not set.
+pub const DEFAULT_GRPC_ENDPOINT: &str = "http://127.0.0.1:50051";
+
+type Connect = Result<
+  (mpsc::Sender<EnvelopeFrame>, Streaming<EnvelopeFrame>),
+  Box<dyn std::error::Error + Send + Sync>,
+>;
+
+pub struct Grpc {
+  endpoint:   Arc<str>,
+  types:      Arc<RwLock<HashMap<u64, TypeId>>>,
+  outbound:   Mutex<Option<mpsc::Sender<EnvelopeFrame>>>,
+  /// What the current stream reads its frames from, kept to recover the frames it never sent.
+  outgoing:   Mutex<Option<Arc<Mutex<mpsc::Receiver<EnvelopeFrame>>>>>,
+  /// Held while a stream is being opened, so that only one attempt runs at a time.
+  connecting: tokio::sync::Mutex<()>,
+  inbound:    Mutex<Option<Streaming<EnvelopeFrame>>>,
+  pending:    Mutex<ResendQueue<EnvelopeFrame>>,
+  backoff:    Backoff,
+  /// When receive may try to reopen the stream, kept across cancelled receives.
+  retry_at:   Option<tokio::time::Instant>,
+  events:     EventLog,
+}
+
+/// The outbound half of a stream. Its receiver is shared with the [`Grpc`] that opened it, so
+/// that frames still queued when the stream fails can be taken back and resent.
+struct Outgoing(Arc<Mutex<mpsc::Receiver<EnvelopeFrame>>>);
+
+impl Stream for Outgoing {
+  type Item = EnvelopeFrame;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<EnvelopeFrame>> {
+    self.0.lock().unwrap().poll_recv(cx)
+  }
+}
+
+impl Drop for Outgoing {
+  // Closing marks the stream's sender closed, so that sends stop handing it frames.
+  fn drop(&mut self) { self.0.lock().unwrap().close(); }
+}
+
+impl std::fmt::Debug for Grpc {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Grpc")
+      .field("endpoint", &self.endpoint)
+      .field("pending", &self.pending.lock().unwrap().len())
+      .finish_non_exhaustive()
+  }
+}
+
+impl Grpc {
+  /// Creates a network that streams through the relay at `endpoint`.
+  ///
+  /// The stream is opened lazily on the first send or receive.
+  pub fn with_endpoint(endpoint: impl Into<String>) -> Self {
+    Self::sharing_types(Arc::from(endpoint.into()), Arc::new(RwLock::new(HashMap::new())))
+  }
+
+  fn sharing_types(endpoint: Arc<str>, types: Arc<RwLock<HashMap<u64, TypeId>>>) -> Self {
+    Self {
+      endpoint,
+      types,
+      outbound: Mutex::new(None),
+      outgoing: Mutex::new(None),
+      connecting: tokio::sync::Mutex::new(()),
+      inbound: Mutex::new(None),
+      pending: Mutex::new(ResendQueue::default()),
+      backoff: Backoff::default(),
+      retry_at: None,
+      events: EventLog::default(),
+    }
+  }
+
+  /// Makes envelopes of type `M` receivable on this network and every network joined from it.
+  pub fn register<M: Message>(&self) {
+    let type_id = TypeId::of::<M>();
+    self.types.write().unwrap().insert(type_hash(type_id), type_id);
+  }
+
+  /// The tag that frames carrying the given `TypeId` are sent with.
+  pub fn type_tag(type_id: TypeId) -> String { format!("{:016x}", type_hash(type_id)) }
+
+  /// Returns the open stream's sender, opening a new stream if there is none, after sending it
+  /// everything queued while there was none.
+  ///
+  /// If another call is already opening the stream, this waits for it when `wait` is set and
+  /// returns `None` otherwise, so that sends queue their frames instead of blocking.
+  async fn open(&self, wait: bool) -> Option<mpsc::Sender<EnvelopeFrame>> {
+    if let Some(sender) = self.current() {
+      return self.resend_pending(sender).await;
+    }
+    let _connecting =
+      if wait { self.connecting.lock().await } else { self.connecting.try_lock().ok()? };
+    // The stream may have been opened while we waited for our turn.
+    if let Some(sender) = self.current() {
+      return self.resend_pending(sender).await;
+    }
+    self.salvage();
+    let connect = async {
+      let mut client = RelayClient::connect(self.endpoint.to_string()).await?;
+      let (sender, receiver) = mpsc::channel(1024);
+      let receiver = Arc::new(Mutex::new(receiver));
+      *self.outgoing.lock().unwrap() = Some(receiver.clone());
+      let inbound = client.stream(Outgoing(receiver)).await?.into_inner();
+      Connect::Ok((sender, inbound))
+    };
+    let (sender, inbound) = match connect.await {
+      Ok(stream) => stream,
+      Err(e) => {
+        tracing::error!("failed to open gRPC stream to {}: {e}", self.endpoint);
+        return None;
+      },
+    };
+    *self.inbound.lock().unwrap() = Some(inbound);
+    *self.outbound.lock().unwrap() = Some(sender.clone());
+    self.events.record(NetworkEvent::Connected);
+    self.resend_pending(sender).await
+  }
+
+  /// Puts the frames a failed stream was handed but never sent back in front of the resend queue.
+  fn salvage(&self) {
+    let Some(outgoing) = self.outgoing.lock().unwrap().take() else {
+      return;
+    };
+    let mut outgoing = outgoing.lock().unwrap();
+    outgoing.close();
+    let mut pending = self.pending.lock().unwrap();
+    let queued: Vec<_> = pending.drain().collect();
+    while let Ok(frame) = outgoing.try_recv() {
+      pending.push(frame);
+    }
+    queued.into_iter().for_each(|frame| pending.push(frame));
+  }
+
+  /// The open stream's sender, if there is one.
+  fn current(&self) -> Option<mpsc::Sender<EnvelopeFrame>> {
+    self.outbound.lock().unwrap().clone().filter(|sender| !sender.is_closed())
+  }
+
+  /// Sends every queued frame on `sender`, and returns it unless the stream turns out to be closed.
+  async fn resend_pending(
+    &self,
+    sender: mpsc::Sender<EnvelopeFrame>,
+  ) -> Option<mpsc::Sender<EnvelopeFrame>> {
+    let pending: Vec<_> = self.pending.lock().unwrap().drain().collect();
+    let mut pending = pending.into_iter();
+    while let Some(frame) = pending.next() {
+      if let Err(e) = sender.send(frame).await {
+        let mut queue = self.pending.lock().unwrap();
+        queue.push(e.0);
+        pending.for_each(|frame| queue.push(frame));
+        return None;
+      }
+    }
+    Some(sender)
+  }
+
+  /// Drops the current stream after a failure and schedules the next attempt to reopen it.
+  fn reconnect(&mut self, reason: String) {
+    if self.outbound.get_mut().unwrap().take().is_some() {
+      self.events.record(NetworkEvent::Disconnected { reason });
+    }
+    *self.inbound.get_mut().unwrap() = None;
+    self.salvage();
+    let delay = self.backoff.next_delay();
+    let attempt = self.backoff.attempt();
+    self.retry_at = Some(tokio::time::Instant::now() + delay);
+    self.events.record(NetworkEvent::Reconnecting { attempt, delay });
+  }
+}
+
+fn type_id_for(types: &RwLock<HashMap<u64, TypeId>>, tag: &str) -> Option<TypeId> {
+  if tag.len() != 16 || !tag.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+    return None;
+  }
+  tagged_type(types, u64::from_str_radix(tag, 16).ok()?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GrpcAddress(u64);
+
+impl GrpcAddress {
+  pub const fn from_u64(id: u64) -> Self { Self(id) }
+
+  pub const fn as_u64(&self) -> u64 { self.0 }
+}
+
+impl FrameAddress for GrpcAddress {
+  fn to_wire(&self) -> u64 { self.0 }
+
+  fn from_wire(id: u64) -> Self { Self(id) }
+}
+
+impl Generateable for GrpcAddress {
+  fn generate() -> Self {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    static COUNTER: AtomicU32 = AtomicU32::new(1);
+    // The process id keeps addresses from colliding between processes sharing a relay.
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    Self((u64::from(std::process::id()) << 32) | u64::from(id))
+  }
+}
+
+impl std::fmt::Display for GrpcAddress {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "grpc-{:016x}", self.0)
+  }
+}
+
+impl Network for Grpc {
+  type Address = GrpcAddress;
+  type Payload = Vec<u8>;
+
+  fn new() -> Self {
+    let endpoint =
+      std::env::var("ARBITER_GRPC_ENDPOINT").unwrap_or_else(|_| DEFAULT_GRPC_ENDPOINT.to_string());
+    Self::with_endpoint(endpoint)
+  }
+
+  fn join(&self) -> Self { Self::sharing_types(self.endpoint.clone(), self.types.clone()) }
+
+  async fn send(&self, envelope: Envelope<Self>) {
+    let type_id = envelope.type_id;
+    self.types.write().unwrap().entry(type_hash(type_id)).or_insert(type_id);
+    let frame = EnvelopeFrame {
+      type_tag: Self::type_tag(type_id),
+      header:   Header::of(&envelope).encode(),
+      payload:  envelope.payload,
+    };
+    let unsent = match self.open(false).await {
+      Some(sender) => sender.send(frame).await.err().map(|e| e.0),
+      None => Some(frame),
+    };
+    if let Some(frame) = unsent {
+      tracing::warn!("gRPC stream to {} is down, queueing frame for resend", self.endpoint);
+      self.pending.lock().unwrap().push(frame);
+    }
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    loop {
+      if let Some(retry_at) = self.retry_at {
+        tokio::time::sleep_until(retry_at).await;
+        self.retry_at = None;
+      }
+      if self.open(true).await.is_none() {
+        self.reconnect("failed to open stream".to_string());
+        continue;
+      }
+      let Some(inbound) = self.inbound.get_mut().unwrap().as_mut() else {
+        self.reconnect("stream has no inbound half".to_string());
+        continue;
+      };
+      let frame = match inbound.message().await {
+        Ok(Some(frame)) => frame,
+        Ok(None) => {
+          tracing::warn!("gRPC stream to {} ended, reconnecting", self.endpoint);
+          self.reconnect("stream ended".to_string());
+          continue;
+        },
+        Err(status) => {
+          tracing::warn!("gRPC stream to {} failed, reconnecting: {status}", self.endpoint);
+          self.reconnect(status.to_string());
+          continue;
+        },
+      };
+      self.backoff.reset();
+      let Some(type_id) = type_id_for(&self.types, &frame.type_tag) else {
+        tracing::debug!("dropping gRPC frame with unknown tag {}", frame.type_tag);
+        continue;
+      };
+      let Some(header) = Header::decode(&frame.header) else {
+        tracing::debug!("dropping gRPC frame with a malformed header");
+        continue;
+      };
+      let mut envelope = Envelope::new(frame.payload, type_id);
+      header.apply(&mut envelope);
+      return Some(envelope);
+    }
+  }
+
+  fn take_events(&mut self) -> Vec<NetworkEvent> { self.events.take() }
+
+  fn event_signal(&self) -> Option<Arc<tokio::sync::Notify>> { Some(self.events.signal()) }
+
+  fn stats(&self) -> NetworkStats {
+    let pending = self.pending.lock().unwrap();
+    NetworkStats {
+      queue_depth: pending.len(),
+      dropped: pending.discarded(),
+      ..NetworkStats::default()
+    }
+  }
+}
+
+/// The relay service that [`Grpc`] networks stream through.
+#[derive(Debug, Clone)]
+pub struct RelayService {
+  frames: broadcast::Sender<EnvelopeFrame>,
+}
+
+impl RelayService {
+  pub fn new(capacity: usize) -> Self { Self { frames: broadcast::channel(capacity).0 } }
+}
+
+impl Default for RelayService {
+  fn default() -> Self { Self::new(1024) }
+}
+
+#[tonic::async_trait]
+impl Relay for RelayService {
+  type StreamStream = Pin<Box<dyn Stream<Item = Result<EnvelopeFrame, Status>> + Send>>;
+
+  async fn stream(
+    &self,
+    request: Request<Streaming<EnvelopeFrame>>,
+  ) -> Result<Response<Self::StreamStream>, Status> {
+    let mut inbound = request.into_inner();
+    let frames = self.frames.clone();
+    let outbound = BroadcastStream::new(frames.subscribe()).filter_map(|frame| match frame {
+      Ok(frame) => Some(Ok(frame)),
+      Err(e) => {
+        tracing::warn!("gRPC relay subscriber {e}");
+        None
+      },
+    });
+    tokio::spawn(async move {
+      while let Ok(Some(frame)) = inbound.message().await {
+        // Sending only fails when no stream is open, in which case there is no one to relay to.
+        let _ = frames.send(frame);
+      }
+    });
+    Ok(Response::new(Box::pin(outbound)))
+  }
+}
+
+/// Runs a relay on `address` until the server fails.
+pub async fn serve(address: SocketAddr) -> Result<(), tonic::transport::Error> {
+  tonic::transport::Server::builder()
+    .add_service(RelayServer::new(RelayService::default()))
+    .serve(address)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+  use crate::fixtures::{NumberMessage, TextMessage};
+
+  #[test]
+  fn test_type_tags_round_trip() {
+    let types = RwLock::new(HashMap::new());
+    let type_id = TypeId::of::<TextMessage>();
+    types.write().unwrap().insert(type_hash(type_id), type_id);
+
+    assert_eq!(type_id_for(&types, &Grpc::type_tag(type_id)), Some(type_id));
+    assert_eq!(type_id_for(&types, &Grpc::type_tag(TypeId::of::<NumberMessage>())), None);
+    assert_eq!(type_id_for(&types, "+000000000000001"), None);
+    assert_eq!(type_id_for(&types, "not-hex"), None);
+  }
+
+  #[test]
+  fn test_frames_a_failed_stream_never_sent_are_resent_first() {
+    let grpc = Grpc::with_endpoint(DEFAULT_GRPC_ENDPOINT);
+    let frame = |tag: &str| EnvelopeFrame {
+      type_tag: tag.to_string(),
+      header:   Vec::new(),
+      payload:  Vec::new(),
+    };
+    let (sender, receiver) = mpsc::channel(8);
+    *grpc.outgoing.lock().unwrap() = Some(Arc::new(Mutex::new(receiver)));
+    sender.try_send(frame("unsent")).unwrap();
+    grpc.pending.lock().unwrap().push(frame("queued"));
+
+    grpc.salvage();
+    assert!(sender.is_closed());
+    let tags: Vec<_> = grpc.pending.lock().unwrap().drain().map(|frame| frame.type_tag).collect();
+    assert_eq!(tags, ["unsent", "queued"]);
+  }
+
+  #[tokio::test]
+  async fn test_relay_forwards_frames_between_connections() {
+    // Find a free port for the relay.
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    tokio::spawn(serve(address));
+    let sender = Grpc::with_endpoint(format!("http://{address}"));
+    let mut receiver = sender.join();
+    let opened = async {
+      loop {
+        if receiver.open(true).await.is_some() {
+          break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    };
+    tokio::time::timeout(Duration::from_secs(5), opened).await.unwrap();
+
+    let type_id = TypeId::of::<TextMessage>();
+    sender.send(Envelope::new(b"hello".to_vec(), type_id)).await;
+    let envelope =
+      tokio::time::timeout(Duration::from_secs(5), receiver.receive()).await.unwrap().unwrap();
+    assert_eq!(envelope.type_id, type_id);
+    assert_eq!(envelope.payload, b"hello");
+  }
+}
@@ -1,14 +1,146 @@
-use std::sync::Arc;
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+  },
+};
+
+use tokio::sync::{broadcast, error::RecvError, mpsc};
 
 use crate::{
-  handler::{Envelope, Message},
-  network::{Generateable, Network},
+  handler::{Delivery, Envelope, Message},
+  network::{Generateable, Network, NetworkEvent, NetworkStats},
 };
 
+/// How many broadcast envelopes, and how many unicast envelopes, [`Network::new`] buffers for each
+/// connection.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// The sending end of a bound connection's mailbox, along with how many envelopes were turned away
+/// because it was full and the connection has not been told about yet.
+#[derive(Debug, Clone)]
+struct Slot {
+  sender:  mpsc::Sender<Envelope<InMemory>>,
+  skipped: Arc<AtomicU64>,
+}
+
+type Mailboxes = Arc<RwLock<HashMap<InMemoryAddress, Slot>>>;
+
+/// A bound connection's mailbox: its address, its end of the channel, and the slot registered for
+/// it, which tells whether the address is still bound to this connection.
+type Mailbox = (InMemoryAddress, mpsc::Receiver<Envelope<InMemory>>, Slot);
+
+/// Unregisters `mailbox`, unless another connection has bound its address since.
+fn release(mailboxes: &mut HashMap<InMemoryAddress, Slot>, mailbox: &Mailbox) {
+  let (address, _, slot) = mailbox;
+  if mailboxes.get(address).is_some_and(|bound| bound.sender.same_channel(&slot.sender)) {
+    mailboxes.remove(address);
+  }
+}
+
+#[derive(Debug, Clone, Copy, thiserror::Error, PartialEq, Eq)]
+pub enum InMemoryError {
+  #[error("no connection is receiving on this network")]
+  NoReceivers,
+}
+
+/// An in-process network. [`Network::send`] broadcasts to every joined connection, while
+/// [`InMemory::send_to`] delivers only to the connection bound to a given address.
+///
+/// Broadcasts are buffered up to a fixed capacity per connection, and so are unicasts to each bound
+/// connection. A connection that falls further behind on broadcasts skips the oldest, while a full
+/// mailbox turns new unicasts away, which count as dropped on the sending connection. Either way
+/// the receiving connection reports how many envelopes it missed as a [`NetworkEvent::Lagged`] so
+/// its agent can resync.
+///
+/// Broadcasts and unicasts are queued separately, and a bound connection takes its unicasts first.
+/// Each kind arrives in the order it was sent, but a unicast can overtake broadcasts sent before
+/// it.
 #[derive(Debug)]
 pub struct InMemory {
-  pub(crate) sender:   tokio::sync::broadcast::Sender<Envelope<Self>>,
-  pub(crate) receiver: tokio::sync::broadcast::Receiver<Envelope<Self>>,
+  pub(crate) sender:    broadcast::Sender<Envelope<Self>>,
+  pub(crate) receiver:  broadcast::Receiver<Envelope<Self>>,
+  pub(crate) mailboxes: Mailboxes,
+  pub(crate) mailbox:   Option<Mailbox>,
+  pub(crate) events:    Vec<NetworkEvent>,
+  pub(crate) lagged:    u64,
+  capacity:             usize,
+  dropped:              AtomicU64,
+}
+
+impl InMemory {
+  /// Creates a network that buffers up to `capacity` broadcast envelopes, and as many unicast
+  /// envelopes, for each connection.
+  ///
+  /// # Panics
+  ///
+  /// If `capacity` is zero.
+  pub fn with_capacity(capacity: usize) -> Self {
+    let (sender, receiver) = broadcast::channel(capacity);
+    Self::connection(sender, receiver, Arc::default(), capacity)
+  }
+
+  fn connection(
+    sender: broadcast::Sender<Envelope<Self>>,
+    receiver: broadcast::Receiver<Envelope<Self>>,
+    mailboxes: Mailboxes,
+    capacity: usize,
+  ) -> Self {
+    Self {
+      sender,
+      receiver,
+      mailboxes,
+      mailbox: None,
+      events: Vec::new(),
+      lagged: 0,
+      capacity,
+      dropped: AtomicU64::new(0),
+    }
+  }
+
+  /// Broadcasts `envelope` to every joined connection, returning how many it reached.
+  pub fn try_send(&self, envelope: Envelope<Self>) -> Result<usize, InMemoryError> {
+    self.sender.send(envelope).map_err(|_| InMemoryError::NoReceivers)
+  }
+
+  /// Delivers `envelope` only to the connection bound to `address`.
+  ///
+  /// Returns `false` if no connection on this network is bound to `address`, or if its mailbox is
+  /// full, in which case the envelope counts as dropped here and as lagged there.
+  pub async fn send_to(&self, address: InMemoryAddress, envelope: Envelope<Self>) -> bool {
+    let mailboxes = self.mailboxes.read().unwrap();
+    let Some(slot) = mailboxes.get(&address) else { return false };
+    match slot.sender.try_send(envelope) {
+      Ok(()) => true,
+      Err(mpsc::error::TrySendError::Full(_)) => {
+        tracing::warn!("the mailbox for {address} is full, dropping an envelope");
+        slot.skipped.fetch_add(1, Ordering::Relaxed);
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        false
+      },
+      Err(mpsc::error::TrySendError::Closed(_)) => false,
+    }
+  }
+
+  fn record_lagged(&mut self, skipped: u64) {
+    tracing::warn!("in-memory connection lagged behind and skipped {skipped} envelopes");
+    self.lagged += skipped;
+    self.events.push(NetworkEvent::Lagged { skipped });
+  }
+
+  /// The address this connection receives unicast envelopes for, if it is bound.
+  pub fn bound_address(&self) -> Option<InMemoryAddress> {
+    self.mailbox.as_ref().map(|(address, ..)| *address)
+  }
+}
+
+impl Drop for InMemory {
+  fn drop(&mut self) {
+    if let Some(mailbox) = self.mailbox.take() {
+      release(&mut self.mailboxes.write().unwrap(), &mailbox);
+    }
+  }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -42,17 +174,176 @@ impl Network for InMemory {
   type Address = InMemoryAddress;
   type Payload = Arc<dyn Message>;
 
-  fn new() -> Self {
-    let (sender, receiver) = tokio::sync::broadcast::channel(1024);
-    Self { sender, receiver }
-  }
+  fn new() -> Self { Self::with_capacity(DEFAULT_CAPACITY) }
 
   fn join(&self) -> Self {
     let (sender, receiver) = (self.sender.clone(), self.sender.subscribe());
-    Self { sender, receiver }
+    Self::connection(sender, receiver, self.mailboxes.clone(), self.capacity)
+  }
+
+  fn bind(&mut self, address: Self::Address) {
+    let (sender, receiver) = mpsc::channel(self.capacity);
+    let slot = Slot { sender, skipped: Arc::default() };
+    let mut mailboxes = self.mailboxes.write().unwrap();
+    if let Some(previous) = self.mailbox.replace((address, receiver, slot.clone())) {
+      release(&mut mailboxes, &previous);
+    }
+    mailboxes.insert(address, slot);
+  }
+
+  /// Broadcasts `envelope`, or delivers it straight to the bound connections it is addressed to.
+  async fn send(&self, envelope: Envelope<Self>) {
+    match envelope.to.clone() {
+      Delivery::Broadcast =>
+        if let Err(e) = self.try_send(envelope) {
+          tracing::debug!("dropping broadcast envelope: {e}");
+        },
+      Delivery::Unicast(address) =>
+        if !self.send_to(address, envelope).await {
+          tracing::debug!("dropping envelope for {address}, which is not bound or is full");
+        },
+      Delivery::Multicast(addresses) =>
+        for address in addresses {
+          if !self.send_to(address, envelope.clone()).await {
+            tracing::debug!("dropping envelope for {address}, which is not bound or is full");
+          }
+        },
+    }
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    loop {
+      let skipped =
+        self.mailbox.as_ref().map_or(0, |(.., slot)| slot.skipped.swap(0, Ordering::Relaxed));
+      if skipped > 0 {
+        self.record_lagged(skipped);
+      }
+      let received = match self.mailbox.as_mut() {
+        Some((_, mailbox, _)) => tokio::select! {
+          biased;
+          Some(envelope) = mailbox.recv() => return Some(envelope),
+          received = self.receiver.recv() => received,
+        },
+        None => self.receiver.recv().await,
+      };
+      match received {
+        Ok(envelope) => return Some(envelope),
+        Err(RecvError::Lagged(skipped)) => self.record_lagged(skipped),
+        Err(RecvError::Closed) => return None,
+      }
+    }
   }
 
-  async fn send(&self, envelope: Envelope<Self>) { self.sender.send(envelope).unwrap(); }
+  fn take_events(&mut self) -> Vec<NetworkEvent> { std::mem::take(&mut self.events) }
 
-  async fn receive(&mut self) -> Option<Envelope<Self>> { self.receiver.recv().await.ok() }
+  fn stats(&self) -> NetworkStats {
+    let mailbox = self.mailbox.as_ref().map_or(0, |(_, mailbox, _)| mailbox.len());
+    NetworkStats {
+      queue_depth: self.receiver.len() + mailbox,
+      dropped: self.dropped.load(Ordering::Relaxed),
+      lagged: self.lagged,
+      ..NetworkStats::default()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{fixtures::TextMessage, network::Connection};
+
+  #[tokio::test]
+  async fn test_send_to_only_reaches_bound_connection() {
+    let network = InMemory::new();
+    let (alice, bob) = (InMemoryAddress::generate(), InMemoryAddress::generate());
+    let mut to_alice = network.join();
+    to_alice.bind(alice);
+    let mut to_bob = network.join();
+    to_bob.bind(bob);
+
+    let envelope = Envelope::package(TextMessage { content: "Hello".to_string() });
+    assert!(network.send_to(alice, envelope).await);
+    let received = to_alice.receive().await.unwrap();
+    assert_eq!(received.unpackage::<TextMessage>().unwrap().content, "Hello");
+    assert!(to_bob.mailbox.as_mut().unwrap().1.try_recv().is_err());
+    assert!(to_bob.receiver.try_recv().is_err());
+
+    drop(to_alice);
+    let envelope = Envelope::package(TextMessage { content: "Hello".to_string() });
+    assert!(!network.send_to(alice, envelope).await);
+  }
+
+  #[tokio::test]
+  async fn test_rejoining_keeps_the_new_mailbox() {
+    let address = InMemoryAddress::generate();
+    let old = Connection::<InMemory>::new(address);
+    let mut new = old.join();
+    drop(old);
+
+    let envelope = Envelope::package(TextMessage { content: "Hello".to_string() });
+    assert!(new.network.send_to(address, envelope).await);
+    let received = new.network.receive().await.unwrap();
+    assert_eq!(received.unpackage::<TextMessage>().unwrap().content, "Hello");
+
+    // Rebinding releases the address only if the connection still holds it.
+    let mut other = new.network.join();
+    other.bind(address);
+    new.network.bind(InMemoryAddress::generate());
+    let envelope = Envelope::package(TextMessage { content: "Hello".to_string() });
+    assert!(new.network.send_to(address, envelope).await);
+  }
+
+  #[tokio::test]
+  async fn test_send_routes_by_delivery() {
+    let network = InMemory::new();
+    let (alice, bob) = (InMemoryAddress::generate(), InMemoryAddress::generate());
+    let mut to_alice = network.join();
+    to_alice.bind(alice);
+    let mut to_bob = network.join();
+    to_bob.bind(bob);
+
+    let envelope = Envelope::package(TextMessage { content: "Hello".to_string() });
+    network.send(envelope.with_delivery(Delivery::Unicast(bob))).await;
+    assert!(to_bob.mailbox.as_mut().unwrap().1.try_recv().is_ok());
+    assert!(to_alice.mailbox.as_mut().unwrap().1.try_recv().is_err());
+    assert!(to_alice.receiver.try_recv().is_err());
+  }
+
+  #[tokio::test]
+  async fn test_lagging_connection_reports_skipped_envelopes() {
+    let network = InMemory::with_capacity(2);
+    let mut slow = network.join();
+    for value in 0..5 {
+      let envelope = Envelope::package(TextMessage { content: value.to_string() });
+      // Both `network` itself and `slow` are subscribed.
+      assert_eq!(network.try_send(envelope), Ok(2));
+    }
+
+    let received = slow.receive().await.unwrap();
+    assert_eq!(received.unpackage::<TextMessage>().unwrap().content, "3");
+    assert_eq!(slow.take_events(), [NetworkEvent::Lagged { skipped: 3 }]);
+    assert!(slow.take_events().is_empty());
+    assert_eq!((slow.stats().lagged, slow.stats().queue_depth), (3, 1));
+  }
+
+  #[tokio::test]
+  async fn test_full_mailboxes_turn_unicasts_away() {
+    let network = InMemory::with_capacity(2);
+    let address = InMemoryAddress::generate();
+    let mut slow = network.join();
+    slow.bind(address);
+    let mut sent = Vec::new();
+    for value in 0..3 {
+      let envelope = Envelope::package(TextMessage { content: value.to_string() });
+      sent.push(network.send_to(address, envelope).await);
+    }
+    assert_eq!(sent, [true, true, false]);
+    assert_eq!(network.stats().dropped, 1);
+    assert_eq!(slow.stats().queue_depth, 2);
+
+    let received = slow.receive().await.unwrap();
+    assert_eq!(received.unpackage::<TextMessage>().unwrap().content, "0");
+    assert_eq!(slow.take_events(), [NetworkEvent::Lagged { skipped: 1 }]);
+    assert_eq!(slow.stats().lagged, 1);
+  }
 }
@@ -0,0 +1,250 @@
+//! A [`Network`] decorator that measures the traffic on a connection.
+//!
+//! [`Metered`] counts the envelopes sent and received on the connection it wraps and samples round
+//! trips, and reports them from [`Network::stats`] together with what the wrapped transport tracks
+//! itself. A [`Meter`] taken from it reads the same counts from outside, so a test or a dashboard
+//! can watch an agent's connection while the agent owns it.
+//!
+//! A round trip is the time from sending an envelope to receiving it back, which every broadcast
+//! transport does. Envelopes are matched to their echo by [`Envelope::from`], which the decorator
+//! stamps with its bound address, so only bound connections sample round trips, and only envelopes
+//! whose delivery includes the sender are expected back. Envelopes lost on the way make later
+//! samples longer until the backlog clears.
+
+use std::{
+  collections::VecDeque,
+  fmt::Debug,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+  handler::Envelope,
+  network::{Network, NetworkEvent, NetworkStats},
+};
+
+/// How many round trips [`Metered`] keeps, and how many sends it waits on to come back.
+pub const DEFAULT_SAMPLES: usize = 256;
+
+#[derive(Debug)]
+struct Counters {
+  started:     Instant,
+  sent:        AtomicU64,
+  received:    AtomicU64,
+  in_flight:   Mutex<VecDeque<Instant>>,
+  round_trips: Mutex<VecDeque<Duration>>,
+  samples:     usize,
+}
+
+/// A handle on the counts of a [`Metered`] connection.
+#[derive(Debug, Clone)]
+pub struct Meter {
+  counters: Arc<Counters>,
+}
+
+impl Meter {
+  fn new(samples: usize) -> Self {
+    Self {
+      counters: Arc::new(Counters {
+        started:     Instant::now(),
+        sent:        AtomicU64::new(0),
+        received:    AtomicU64::new(0),
+        in_flight:   Mutex::new(VecDeque::new()),
+        round_trips: Mutex::new(VecDeque::new()),
+        samples:     samples.max(1),
+      }),
+    }
+  }
+
+  /// The traffic counted so far. Fields only the transport tracks are left empty.
+  pub fn snapshot(&self) -> NetworkStats {
+    let counters = &self.counters;
+    NetworkStats {
+      sent: counters.sent.load(Ordering::Relaxed),
+      received: counters.received.load(Ordering::Relaxed),
+      round_trips: counters.round_trips.lock().unwrap().iter().copied().collect(),
+      elapsed: counters.started.elapsed(),
+      ..NetworkStats::default()
+    }
+  }
+
+  fn sent(&self, echoed: bool) {
+    let counters = &self.counters;
+    counters.sent.fetch_add(1, Ordering::Relaxed);
+    if echoed {
+      let mut in_flight = counters.in_flight.lock().unwrap();
+      if in_flight.len() == counters.samples {
+        in_flight.pop_front();
+      }
+      in_flight.push_back(Instant::now());
+    }
+  }
+
+  fn received(&self, echo: bool) {
+    let counters = &self.counters;
+    counters.received.fetch_add(1, Ordering::Relaxed);
+    if !echo {
+      return;
+    }
+    let Some(sent_at) = counters.in_flight.lock().unwrap().pop_front() else { return };
+    let mut round_trips = counters.round_trips.lock().unwrap();
+    if round_trips.len() == counters.samples {
+      round_trips.pop_front();
+    }
+    round_trips.push_back(sent_at.elapsed());
+  }
+}
+
+pub struct Metered<N: Network> {
+  inner:   N,
+  address: Option<N::Address>,
+  meter:   Meter,
+  samples: usize,
+}
+
+impl<N: Network + Debug> Debug for Metered<N> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Metered")
+      .field("inner", &self.inner)
+      .field("address", &self.address)
+      .finish_non_exhaustive()
+  }
+}
+
+impl<N: Network> Metered<N> {
+  /// Wraps `inner`, keeping up to `samples` round trips on this connection and every connection
+  /// joined from it. Each connection is metered separately.
+  pub fn with_samples(inner: N, samples: usize) -> Self {
+    Self { inner, address: None, meter: Meter::new(samples), samples }
+  }
+
+  /// A handle that reads this connection's counts.
+  pub fn meter(&self) -> Meter { self.meter.clone() }
+
+  pub const fn inner(&self) -> &N { &self.inner }
+}
+
+impl<N: Network> Network for Metered<N> {
+  type Address = N::Address;
+  type Payload = N::Payload;
+
+  fn new() -> Self { Self::with_samples(N::new(), DEFAULT_SAMPLES) }
+
+  fn join(&self) -> Self { Self::with_samples(self.inner.join(), self.samples) }
+
+  fn bind(&mut self, address: Self::Address) {
+    self.address = Some(address);
+    self.inner.bind(address);
+  }
+
+  async fn send(&self, envelope: Envelope<Self>) {
+    let mut envelope: Envelope<N> = envelope.map_payload(std::convert::identity);
+    envelope.from = envelope.from.or(self.address);
+    // Unicasts and multicasts that leave the sender out never come back to it.
+    let echoed = self
+      .address
+      .is_some_and(|address| envelope.from == Some(address) && envelope.to.includes(&address));
+    self.meter.sent(echoed);
+    self.inner.send(envelope).await;
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    let envelope = self.inner.receive().await?;
+    // Byte transports hand back even the envelopes addressed to others, which were not counted.
+    let echo = self
+      .address
+      .is_some_and(|address| envelope.from == Some(address) && envelope.to.includes(&address));
+    self.meter.received(echo);
+    Some(envelope.map_payload(std::convert::identity))
+  }
+
+  fn take_events(&mut self) -> Vec<NetworkEvent> { self.inner.take_events() }
+
+  fn event_signal(&self) -> Option<std::sync::Arc<tokio::sync::Notify>> {
+    self.inner.event_signal()
+  }
+
+  async fn flush(&self) { self.inner.flush().await; }
+
+  fn stats(&self) -> NetworkStats {
+    let NetworkStats { sent, received, round_trips, elapsed, .. } = self.meter.snapshot();
+    NetworkStats { sent, received, round_trips, elapsed, ..self.inner.stats() }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    fixtures::TextMessage,
+    handler::Delivery,
+    network::{
+      memory::{InMemory, InMemoryAddress},
+      Generateable,
+    },
+  };
+
+  #[tokio::test]
+  async fn test_metered_counts_traffic_and_round_trips() {
+    let network = InMemory::new();
+    let mut alice = Metered::with_samples(network.join(), 4);
+    alice.bind(InMemoryAddress::generate());
+    let mut bob = Metered::with_samples(network.join(), 4);
+    let meter = bob.meter();
+
+    alice.send(Envelope::package(TextMessage { content: "Hello".to_string() })).await;
+    assert!(alice.receive().await.is_some());
+    assert!(bob.receive().await.is_some());
+
+    let stats = alice.stats();
+    assert_eq!((stats.sent, stats.received, stats.round_trips.len()), (1, 1, 1));
+    let stats = meter.snapshot();
+    assert_eq!((stats.sent, stats.received), (0, 1));
+    assert!(stats.round_trips.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_round_trips_ignore_envelopes_sent_elsewhere() {
+    let network = InMemory::new();
+    let mut alice = Metered::with_samples(network.join(), 4);
+    alice.bind(InMemoryAddress::generate());
+    let mut bob = network.join();
+    let bob_address = InMemoryAddress::generate();
+    bob.bind(bob_address);
+
+    let text = || Envelope::package(TextMessage { content: "Hello".to_string() });
+    alice.send(text().with_delivery(Delivery::Unicast(bob_address))).await;
+    assert!(bob.receive().await.is_some());
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    alice.send(text()).await;
+    assert!(alice.receive().await.is_some());
+
+    // Measured from the broadcast, not from the unicast before it.
+    let round_trips = alice.stats().round_trips;
+    assert_eq!(round_trips.len(), 1);
+    assert!(round_trips[0] < std::time::Duration::from_millis(20));
+  }
+
+  #[tokio::test]
+  async fn test_rates_over_a_window() {
+    let network = InMemory::new();
+    let alice = Metered::with_samples(network.join(), 4);
+    alice.send(Envelope::package(TextMessage { content: "Hello".to_string() })).await;
+    let earlier = alice.stats();
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    for _ in 0..2 {
+      alice.send(Envelope::package(TextMessage { content: "Hello".to_string() })).await;
+    }
+
+    let stats = alice.stats();
+    let window = stats.since(&earlier);
+    assert_eq!((stats.sent, window.sent), (3, 2));
+    assert!(window.elapsed < stats.elapsed);
+    assert!(window.send_rate() > 0.0);
+  }
+}
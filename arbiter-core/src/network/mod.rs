@@ -4,8 +4,148 @@ use crate::handler::{Envelope, Message, Package};
 
 #[cfg(feature = "in-memory")] pub mod memory;
 
+#[cfg(feature = "compression")] pub mod compressed;
+
+#[cfg(feature = "grpc")] pub mod grpc;
+
+#[cfg(feature = "mqtt")] pub mod mqtt;
+
+#[cfg(feature = "nats")] pub mod nats;
+
+#[cfg(feature = "encryption")] pub mod shared_key;
+
+pub mod batch;
+pub mod bridge;
+pub mod frame;
+pub mod metered;
+pub mod reconnect;
+pub mod recorded;
+pub mod registry;
+pub mod sequenced;
+pub mod simulated;
+pub mod tags;
+pub mod versioned;
+
 #[cfg(feature = "tcp")] pub mod tcp;
 
+#[cfg(feature = "zmq")] pub mod zmq;
+
+/// A tag for a message type, for transports that cannot carry a `TypeId`. Types registered in
+/// [`TypeTags::global`](tags::TypeTags::global) use their registered tag, which is stable across
+/// processes. Any other type falls back to a hash of its `TypeId`, which is only stable within a
+/// build.
+pub(crate) fn type_hash(type_id: std::any::TypeId) -> u64 {
+  use std::hash::{DefaultHasher, Hasher};
+  if let Some(tag) = tags::TypeTags::global().tag_of(type_id) {
+    return tag;
+  }
+  let mut hasher = DefaultHasher::new();
+  type_id.hash(&mut hasher);
+  hasher.finish()
+}
+
+/// The message type tagged `tag`. Registered tags are looked up first, since they are what peers
+/// agree on, and only then a transport's own `types`, whose hashed tags may collide with them.
+pub(crate) fn tagged_type(
+  types: &std::sync::RwLock<std::collections::HashMap<u64, std::any::TypeId>>,
+  tag: u64,
+) -> Option<std::any::TypeId> {
+  tags::TypeTags::global().type_of(tag).or_else(|| types.read().unwrap().get(&tag).copied())
+}
+
+/// Something that happened to a connection besides delivering envelopes, reported to the agent
+/// that owns it through [`LifeCycle::on_network_event`](crate::agent::LifeCycle::on_network_event).
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkEvent {
+  /// The connection reached its transport, for the first time or after a failure.
+  Connected,
+  /// The connection lost its transport.
+  Disconnected { reason: String },
+  /// The connection will try to reach its transport again after `delay`.
+  Reconnecting { attempt: u32, delay: std::time::Duration },
+  /// The connection fell behind and skipped `skipped` envelopes, so state derived from what it
+  /// received may need to be resynchronized.
+  Lagged { skipped: u64 },
+  /// A peer speaks a protocol this connection cannot read, so its envelopes are dropped.
+  Incompatible { reason: String },
+}
+
+/// The [`NetworkEvent`]s a connection has recorded and not yet handed out, for transports that
+/// record them outside of [`Network::receive`], such as while retrying inside it or from a
+/// background task. Clones share the same log.
+#[derive(Debug, Clone, Default)]
+pub struct EventLog {
+  events:   std::sync::Arc<std::sync::Mutex<Vec<NetworkEvent>>>,
+  recorded: std::sync::Arc<tokio::sync::Notify>,
+}
+
+impl EventLog {
+  pub fn record(&self, event: NetworkEvent) {
+    self.events.lock().unwrap().push(event);
+    self.recorded.notify_one();
+  }
+
+  /// Takes every event recorded so far, oldest first.
+  pub fn take(&self) -> Vec<NetworkEvent> { std::mem::take(&mut *self.events.lock().unwrap()) }
+
+  /// Notified whenever an event is recorded, for [`Network::event_signal`].
+  pub fn signal(&self) -> std::sync::Arc<tokio::sync::Notify> { self.recorded.clone() }
+}
+
+/// What a connection has seen of its transport, from [`Network::stats`].
+///
+/// Transports fill in what they track themselves, such as how many envelopes are waiting to be
+/// received or were dropped. Traffic counts and round trips are measured by wrapping a connection
+/// in [`Metered`](metered::Metered).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkStats {
+  /// Envelopes sent on this connection.
+  pub sent:        u64,
+  /// Envelopes received on this connection.
+  pub received:    u64,
+  /// Envelopes waiting to be received, or held back before being sent, such as while the transport
+  /// is down.
+  pub queue_depth: usize,
+  /// Envelopes discarded because a queue was full or they could not be sent.
+  pub dropped:     u64,
+  /// Envelopes skipped because this connection fell behind.
+  pub lagged:      u64,
+  /// The most recent round trips, oldest first.
+  pub round_trips: Vec<std::time::Duration>,
+  /// How long the counts above were collected over.
+  pub elapsed:     std::time::Duration,
+}
+
+impl NetworkStats {
+  /// Envelopes sent per second, averaged over all of [`NetworkStats::elapsed`]. For the rate over a
+  /// recent window, take it from [`NetworkStats::since`] an earlier snapshot.
+  pub fn send_rate(&self) -> f64 { Self::rate(self.sent, self.elapsed) }
+
+  /// Envelopes received per second, averaged over all of [`NetworkStats::elapsed`]. For the rate
+  /// over a recent window, take it from [`NetworkStats::since`] an earlier snapshot.
+  pub fn receive_rate(&self) -> f64 { Self::rate(self.received, self.elapsed) }
+
+  /// What the connection counted between `earlier` and these stats, both taken from the same
+  /// connection. The queue depth and round trips are left as they are now.
+  pub fn since(&self, earlier: &Self) -> Self {
+    Self {
+      sent: self.sent.saturating_sub(earlier.sent),
+      received: self.received.saturating_sub(earlier.received),
+      dropped: self.dropped.saturating_sub(earlier.dropped),
+      lagged: self.lagged.saturating_sub(earlier.lagged),
+      elapsed: self.elapsed.saturating_sub(earlier.elapsed),
+      ..self.clone()
+    }
+  }
+
+  fn rate(count: u64, elapsed: std::time::Duration) -> f64 {
+    if elapsed.is_zero() {
+      return 0.0;
+    }
+    count as f64 / elapsed.as_secs_f64()
+  }
+}
+
 pub trait Generateable {
   fn generate() -> Self;
 }
@@ -18,14 +158,21 @@ pub struct Connection<N: Network> {
 
 impl<N: Network> Connection<N> {
   pub fn new(address: N::Address) -> Self {
-    let channel = N::new();
+    let mut channel = N::new();
+    channel.bind(address);
     Self { address, network: channel }
   }
 
-  pub fn join(&self) -> Self {
-    let channel = self.network.join();
-    Self { address: self.address, network: channel }
+  /// Joins `network` as `address`.
+  pub fn joined(network: &N, address: N::Address) -> Self {
+    let mut channel = network.join();
+    channel.bind(address);
+    Self { address, network: channel }
   }
+
+  /// Joins this connection's network under the same address. The new connection takes over any
+  /// messages addressed to it.
+  pub fn join(&self) -> Self { Self::joined(&self.network, self.address) }
 }
 
 pub trait Network: Send + Sync + Sized + 'static {
@@ -42,6 +189,31 @@ pub trait Network: Send + Sync + Sized + 'static {
 
   fn new() -> Self;
   fn join(&self) -> Self;
+
+  /// Tells this connection which address it receives for. Transports that can deliver to a single
+  /// address use this to route those envelopes here; the rest can ignore it.
+  fn bind(&mut self, _address: Self::Address) {}
+
   fn send(&self, envelope: Envelope<Self>) -> impl std::future::Future<Output = ()> + Send;
   fn receive(&mut self) -> impl std::future::Future<Output = Option<Envelope<Self>>> + Send;
+
+  /// Takes the [`NetworkEvent`]s this connection has recorded since the last call. Agents call
+  /// this after every receive, and whenever the [`Network::event_signal`] is notified. Transports
+  /// that cannot fail return nothing.
+  fn take_events(&mut self) -> Vec<NetworkEvent> { Vec::new() }
+
+  /// Notified when this connection records an event while no receive has returned, such as while
+  /// it is reconnecting, so that agents can handle the event without waiting for an envelope.
+  /// Agents then drop the receive in progress, so transports that record events this way must
+  /// keep their receives cancel-safe. Transports that only record events as a receive returns
+  /// have no signal.
+  fn event_signal(&self) -> Option<std::sync::Arc<tokio::sync::Notify>> { None }
+
+  /// What this connection has seen of its transport so far. Transports that track nothing return
+  /// empty stats.
+  fn stats(&self) -> NetworkStats { NetworkStats::default() }
+
+  /// Sends anything this connection is holding back. Agents call this when they stop, so that
+  /// their last messages go out. Transports that send right away do nothing.
+  fn flush(&self) -> impl std::future::Future<Output = ()> + Send { async {} }
 }
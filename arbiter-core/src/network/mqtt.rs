@@ -0,0 +1,352 @@
+//! A [`Network`] backed by an MQTT broker.
+//!
+//! Every message type is published on its own topic, `<prefix>/<type tag>`, and every connection
+//! subscribes to `<prefix>/#` with its own client id. The QoS used for a message type can be set
+//! with [`Mqtt::set_qos`]. Like the NATS transport, topics are derived from the message's
+//! [tag](super::tags), so peers must register the same tags or be built from the same source, and
+//! a connection can only hand back envelopes for registered types and types it has sent or
+//! [`Mqtt::register`]ed. Payloads are published behind a [frame header](super::frame) carrying
+//! the sender, signature and recipients.
+//!
+//! Each connection drives its `rumqttc` event loop from a background task, so publishes go out
+//! whether or not the connection is ever received from, and what arrives is held until it is.
+//! Reconnecting is left to `rumqttc`: when the event loop reports a connection error, the task
+//! waits out a [`Backoff`] and polls it again, which reconnects and resubscribes. Publishes made
+//! while disconnected wait in the client's request queue. Each loss and recovery is reported as a
+//! [`NetworkEvent`] as soon as it happens.
+
+use std::{
+  any::TypeId,
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+  },
+  time::Duration,
+};
+
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{
+  handler::{Envelope, Message},
+  network::{
+    frame::{self, FrameAddress},
+    reconnect::Backoff,
+    tagged_type, type_hash, EventLog, Generateable, Network, NetworkEvent, NetworkStats,
+  },
+};
+
+/// The broker used by [`Network::new`] when `ARBITER_MQTT_HOST` is not set.
+pub const DEFAULT_MQTT_HOST: &str = "127.0.0.1";
+
+/// The broker port used by [`Network::new`] when `ARBITER_MQTT_PORT` is not set.
+pub const DEFAULT_MQTT_PORT: u16 = 1883;
+
+/// The topic prefix used by [`Network::new`].
+pub const DEFAULT_TOPIC_PREFIX: &str = "arbiter";
+
+/// Settings shared by an [`Mqtt`] network and every network joined from it.
+#[derive(Debug)]
+struct Shared {
+  host:        String,
+  port:        u16,
+  prefix:      String,
+  default_qos: QoS,
+  qos:         RwLock<HashMap<TypeId, QoS>>,
+  types:       RwLock<HashMap<u64, TypeId>>,
+}
+
+impl Shared {
+  /// The QoS connections subscribe with: the highest of the default and every per-type QoS, since
+  /// the broker never delivers above the subscription's QoS.
+  fn subscription_qos(&self) -> QoS {
+    let qos = self.qos.read().unwrap();
+    qos.values().copied().fold(self.default_qos, |highest, qos| {
+      if qos as u8 > highest as u8 {
+        qos
+      } else {
+        highest
+      }
+    })
+  }
+
+  /// The type published on `topic`, if it is one of this network's topics for a known type.
+  fn type_id_for(&self, topic: &str) -> Option<TypeId> {
+    let hash = topic.strip_prefix(self.prefix.as_str())?.strip_prefix('/')?;
+    if hash.len() != 16 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+      return None;
+    }
+    tagged_type(&self.types, u64::from_str_radix(hash, 16).ok()?)
+  }
+}
+
+pub struct Mqtt {
+  shared:     Arc<Shared>,
+  client:     AsyncClient,
+  inbox:      mpsc::UnboundedReceiver<(TypeId, Vec<u8>)>,
+  events:     EventLog,
+  event_loop: JoinHandle<()>,
+  /// Envelopes that could not be published.
+  dropped:    AtomicU64,
+}
+
+impl std::fmt::Debug for Mqtt {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Mqtt")
+      .field("host", &self.shared.host)
+      .field("port", &self.shared.port)
+      .field("prefix", &self.shared.prefix)
+      .finish_non_exhaustive()
+  }
+}
+
+impl Mqtt {
+  /// Creates a network on the broker at `host:port`, publishing under `prefix` with `default_qos`,
+  /// and starts driving its connection in the background.
+  ///
+  /// # Panics
+  ///
+  /// If called outside a tokio runtime.
+  pub fn with_broker(
+    host: impl Into<String>,
+    port: u16,
+    prefix: impl Into<String>,
+    default_qos: QoS,
+  ) -> Self {
+    Self::connect(Arc::new(Shared {
+      host: host.into(),
+      port,
+      prefix: prefix.into(),
+      default_qos,
+      qos: RwLock::new(HashMap::new()),
+      types: RwLock::new(HashMap::new()),
+    }))
+  }
+
+  /// Makes envelopes of type `M` receivable on this network and every network joined from it.
+  pub fn register<M: Message>(&self) {
+    let type_id = TypeId::of::<M>();
+    self.shared.types.write().unwrap().insert(type_hash(type_id), type_id);
+  }
+
+  /// Publishes messages of type `M` with `qos` instead of the network's default, on this network
+  /// and every network joined from it.
+  ///
+  /// Raising a type's QoS above the default also raises the QoS every connection subscribes with.
+  /// This connection resubscribes right away and the others by their next keep-alive, so messages
+  /// that must not be downgraded should have their QoS set before joining.
+  pub fn set_qos<M: Message>(&self, qos: QoS) {
+    self.shared.qos.write().unwrap().insert(TypeId::of::<M>(), qos);
+    subscribe(&self.client, &self.shared);
+  }
+
+  /// The topic that envelopes with the given `TypeId` are published on.
+  pub fn topic(&self, type_id: TypeId) -> String {
+    format!("{}/{:016x}", self.shared.prefix, type_hash(type_id))
+  }
+
+  fn connect(shared: Arc<Shared>) -> Self {
+    let mut options = MqttOptions::new(client_id(), shared.host.clone(), shared.port);
+    options.set_keep_alive(Duration::from_secs(5));
+    let (client, event_loop) = AsyncClient::new(options, 1024);
+    let (sender, inbox) = mpsc::unbounded_channel();
+    let events = EventLog::default();
+    let event_loop =
+      tokio::spawn(drive(event_loop, client.clone(), shared.clone(), sender, events.clone()));
+    Self { shared, client, inbox, events, event_loop, dropped: AtomicU64::new(0) }
+  }
+}
+
+/// Subscribes `client` to every topic under the network's prefix, returning the QoS it asked for.
+fn subscribe(client: &AsyncClient, shared: &Shared) -> QoS {
+  let filter = format!("{}/#", shared.prefix);
+  let qos = shared.subscription_qos();
+  if let Err(e) = client.try_subscribe(filter, qos) {
+    tracing::error!("failed to queue MQTT subscription: {e}");
+  }
+  qos
+}
+
+/// Polls `event_loop` until the connection is dropped, passing publishes on to `inbox`.
+async fn drive(
+  mut event_loop: EventLoop,
+  client: AsyncClient,
+  shared: Arc<Shared>,
+  inbox: mpsc::UnboundedSender<(TypeId, Vec<u8>)>,
+  events: EventLog,
+) {
+  let mut backoff = Backoff::default();
+  let mut subscribed = subscribe(&client, &shared);
+  loop {
+    // Another connection may have raised the subscription QoS with `set_qos`.
+    if shared.subscription_qos() != subscribed {
+      subscribed = subscribe(&client, &shared);
+    }
+    match event_loop.poll().await {
+      Ok(Event::Incoming(Packet::Publish(publish))) => match shared.type_id_for(&publish.topic) {
+        Some(type_id) =>
+          if inbox.send((type_id, publish.payload.to_vec())).is_err() {
+            return;
+          },
+        None => tracing::debug!("dropping MQTT message on unknown topic {}", publish.topic),
+      },
+      Ok(Event::Incoming(Packet::ConnAck(ack))) => {
+        // A clean session forgets subscriptions, so renew ours after reconnecting.
+        if backoff.attempt() > 0 && !ack.session_present {
+          subscribed = subscribe(&client, &shared);
+        }
+        backoff.reset();
+        events.record(NetworkEvent::Connected);
+      },
+      Ok(_) => {},
+      Err(e) => {
+        if backoff.attempt() == 0 {
+          events.record(NetworkEvent::Disconnected { reason: e.to_string() });
+        }
+        let delay = backoff.next_delay();
+        tracing::warn!("MQTT connection error, reconnecting in {delay:?}: {e}");
+        events.record(NetworkEvent::Reconnecting { attempt: backoff.attempt(), delay });
+        tokio::time::sleep(delay).await;
+      },
+    }
+  }
+}
+
+fn client_id() -> String {
+  use std::sync::atomic::AtomicU32;
+  static COUNTER: AtomicU32 = AtomicU32::new(1);
+  format!("arbiter-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MqttAddress(u64);
+
+impl MqttAddress {
+  pub const fn from_u64(id: u64) -> Self { Self(id) }
+
+  pub const fn as_u64(&self) -> u64 { self.0 }
+}
+
+impl FrameAddress for MqttAddress {
+  fn to_wire(&self) -> u64 { self.0 }
+
+  fn from_wire(id: u64) -> Self { Self(id) }
+}
+
+impl Generateable for MqttAddress {
+  fn generate() -> Self {
+    use std::sync::atomic::AtomicU32;
+    static COUNTER: AtomicU32 = AtomicU32::new(1);
+    // The process id keeps addresses from colliding between processes sharing a broker.
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    Self((u64::from(std::process::id()) << 32) | u64::from(id))
+  }
+}
+
+impl std::fmt::Display for MqttAddress {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "mqtt-{:016x}", self.0)
+  }
+}
+
+impl Drop for Mqtt {
+  fn drop(&mut self) { self.event_loop.abort(); }
+}
+
+impl Network for Mqtt {
+  type Address = MqttAddress;
+  type Payload = Vec<u8>;
+
+  fn new() -> Self {
+    let host = std::env::var("ARBITER_MQTT_HOST").unwrap_or_else(|_| DEFAULT_MQTT_HOST.to_string());
+    let port = std::env::var("ARBITER_MQTT_PORT")
+      .ok()
+      .and_then(|port| port.parse().ok())
+      .unwrap_or(DEFAULT_MQTT_PORT);
+    Self::with_broker(host, port, DEFAULT_TOPIC_PREFIX, QoS::AtLeastOnce)
+  }
+
+  fn join(&self) -> Self { Self::connect(self.shared.clone()) }
+
+  async fn send(&self, envelope: Envelope<Self>) {
+    let type_id = envelope.type_id;
+    self.shared.types.write().unwrap().entry(type_hash(type_id)).or_insert(type_id);
+    let qos = self.shared.qos.read().unwrap().get(&type_id).copied();
+    let qos = qos.unwrap_or(self.shared.default_qos);
+    let payload = frame::frame(&envelope);
+    if let Err(e) = self.client.publish(self.topic(type_id), qos, false, payload).await {
+      tracing::error!("failed to publish to MQTT: {e}");
+      self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    loop {
+      let (type_id, framed) = self.inbox.recv().await?;
+      match frame::unframe(&framed, type_id) {
+        Some(envelope) => return Some(envelope),
+        None => tracing::debug!("dropping malformed MQTT message"),
+      }
+    }
+  }
+
+  fn take_events(&mut self) -> Vec<NetworkEvent> { self.events.take() }
+
+  fn event_signal(&self) -> Option<Arc<tokio::sync::Notify>> { Some(self.events.signal()) }
+
+  /// Messages that arrived but were not yet received count towards the queue depth, and envelopes
+  /// that could not be published count as dropped. Publishes waiting in the client's request queue
+  /// are not counted.
+  fn stats(&self) -> NetworkStats {
+    NetworkStats {
+      queue_depth: self.inbox.len(),
+      dropped: self.dropped.load(Ordering::Relaxed),
+      ..NetworkStats::default()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{NumberMessage, TextMessage};
+
+  fn shared(default_qos: QoS) -> Shared {
+    Shared {
+      host: DEFAULT_MQTT_HOST.to_string(),
+      port: DEFAULT_MQTT_PORT,
+      prefix: "arbiter".to_string(),
+      default_qos,
+      qos: RwLock::new(HashMap::new()),
+      types: RwLock::new(HashMap::new()),
+    }
+  }
+
+  #[test]
+  fn test_topics_resolve_to_types() {
+    let shared = shared(QoS::AtLeastOnce);
+    let type_id = TypeId::of::<TextMessage>();
+    shared.types.write().unwrap().insert(type_hash(type_id), type_id);
+    let tag = type_hash(type_id);
+
+    assert_eq!(shared.type_id_for(&format!("arbiter/{tag:016x}")), Some(type_id));
+    let unknown = type_hash(TypeId::of::<NumberMessage>());
+    assert_eq!(shared.type_id_for(&format!("arbiter/{unknown:016x}")), None);
+    assert_eq!(shared.type_id_for(&format!("elsewhere/{tag:016x}")), None);
+    assert_eq!(shared.type_id_for(&format!("arbiter/nested/{tag:016x}")), None);
+    assert_eq!(shared.type_id_for("arbiter/+000000000000001"), None);
+    assert_eq!(shared.type_id_for("arbiter/not-hex"), None);
+    assert_eq!(shared.type_id_for(""), None);
+  }
+
+  #[test]
+  fn test_subscription_follows_the_highest_qos() {
+    let shared = shared(QoS::AtLeastOnce);
+    assert_eq!(shared.subscription_qos(), QoS::AtLeastOnce);
+    shared.qos.write().unwrap().insert(TypeId::of::<NumberMessage>(), QoS::AtMostOnce);
+    assert_eq!(shared.subscription_qos(), QoS::AtLeastOnce);
+    shared.qos.write().unwrap().insert(TypeId::of::<TextMessage>(), QoS::ExactlyOnce);
+    assert_eq!(shared.subscription_qos(), QoS::ExactlyOnce);
+  }
+}
@@ -0,0 +1,306 @@
+//! A [`Network`] backed by NATS subjects.
+//!
+//! Every message type is published on its own subject, `<prefix>.<type tag>`, and every
+//! connection subscribes to `<prefix>.>` as soon as it is created, from a background task that
+//! holds what arrives until it is received. Messages published before the subscription is in place
+//! are not delivered to it; [`Nats::ready`] waits until it is. If the server cannot be reached or
+//! the subscription ends, the task tries again after a [`Backoff`], reporting each loss and
+//! recovery as a [`NetworkEvent`]. Once connected, the client itself reconnects to the server and
+//! renews its subscriptions. Subjects are derived from the
+//! message's [tag](super::tags), so peers must register the same tags, or be built from the same
+//! source, to agree on them. A connection can only hand back envelopes for registered types and
+//! types it has sent or [`Nats::register`]ed. Payloads are published behind a [frame
+//! header](super::frame) carrying the sender, signature and recipients.
+
+use std::{
+  any::TypeId,
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+  },
+};
+
+use futures::StreamExt;
+use tokio::{
+  sync::{mpsc, watch, Notify, OnceCell},
+  task::JoinHandle,
+};
+
+use crate::{
+  handler::{Envelope, Message},
+  network::{
+    frame::{self, FrameAddress},
+    reconnect::Backoff,
+    tagged_type, type_hash, EventLog, Generateable, Network, NetworkEvent, NetworkStats,
+  },
+};
+
+/// The server used by [`Network::new`] when `ARBITER_NATS_URL` is not set.
+pub const DEFAULT_NATS_URL: &str = "nats://127.0.0.1:4222";
+
+/// The subject prefix used by [`Network::new`].
+pub const DEFAULT_SUBJECT_PREFIX: &str = "arbiter";
+
+pub struct Nats {
+  url:          Arc<str>,
+  prefix:       Arc<str>,
+  client:       Arc<OnceCell<async_nats::Client>>,
+  types:        Arc<RwLock<HashMap<u64, TypeId>>>,
+  inbox:        mpsc::UnboundedReceiver<async_nats::Message>,
+  subscribed:   watch::Receiver<bool>,
+  subscription: JoinHandle<()>,
+  events:       EventLog,
+  /// Envelopes that could not be published.
+  dropped:      AtomicU64,
+}
+
+impl std::fmt::Debug for Nats {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Nats")
+      .field("url", &self.url)
+      .field("prefix", &self.prefix)
+      .field("connected", &self.client.initialized())
+      .finish_non_exhaustive()
+  }
+}
+
+impl Nats {
+  /// Creates a network that connects to `url` and publishes under `prefix`, and starts
+  /// subscribing in the background.
+  ///
+  /// # Panics
+  ///
+  /// If called outside a tokio runtime.
+  pub fn with_url(url: impl Into<String>, prefix: impl Into<String>) -> Self {
+    Self::subscribe(
+      Arc::from(url.into()),
+      Arc::from(prefix.into()),
+      Arc::new(OnceCell::new()),
+      Arc::new(RwLock::new(HashMap::new())),
+    )
+  }
+
+  fn subscribe(
+    url: Arc<str>,
+    prefix: Arc<str>,
+    client: Arc<OnceCell<async_nats::Client>>,
+    types: Arc<RwLock<HashMap<u64, TypeId>>>,
+  ) -> Self {
+    let (sender, inbox) = mpsc::unbounded_channel();
+    let (ready, subscribed) = watch::channel(false);
+    let events = EventLog::default();
+    let subscription = tokio::spawn(forward(
+      url.clone(),
+      format!("{prefix}.>"),
+      client.clone(),
+      sender,
+      ready,
+      events.clone(),
+    ));
+    Self {
+      url,
+      prefix,
+      client,
+      types,
+      inbox,
+      subscribed,
+      subscription,
+      events,
+      dropped: AtomicU64::new(0),
+    }
+  }
+
+  /// Waits until this connection is subscribed, so that everything published from then on reaches
+  /// it. While the server cannot be reached this keeps waiting, since the connection keeps trying.
+  /// Returns `false` if the background task has stopped.
+  pub async fn ready(&self) -> bool {
+    let mut subscribed = self.subscribed.clone();
+    subscribed.wait_for(|subscribed| *subscribed).await.is_ok()
+  }
+
+  /// Makes envelopes of type `M` receivable on this network and every network joined from it.
+  pub fn register<M: Message>(&self) {
+    let type_id = TypeId::of::<M>();
+    self.types.write().unwrap().insert(type_hash(type_id), type_id);
+  }
+
+  /// The subject that envelopes with the given `TypeId` are published on.
+  pub fn subject(&self, type_id: TypeId) -> String {
+    format!("{}.{:016x}", self.prefix, type_hash(type_id))
+  }
+}
+
+async fn connect<'a>(
+  client: &'a OnceCell<async_nats::Client>,
+  url: &str,
+) -> Result<&'a async_nats::Client, async_nats::ConnectError> {
+  client.get_or_try_init(|| async_nats::connect(url)).await
+}
+
+/// Subscribes to `subject` and passes what arrives on to `inbox` until the connection is dropped,
+/// trying again after a [`Backoff`] whenever connecting or subscribing fails or the subscription
+/// ends.
+async fn forward(
+  url: Arc<str>,
+  subject: String,
+  client: Arc<OnceCell<async_nats::Client>>,
+  inbox: mpsc::UnboundedSender<async_nats::Message>,
+  ready: watch::Sender<bool>,
+  events: EventLog,
+) {
+  let mut backoff = Backoff::default();
+  loop {
+    let reason = match subscribe_to(&client, &url, &subject).await {
+      Ok(mut subscriber) => {
+        backoff.reset();
+        let _ = ready.send(true);
+        events.record(NetworkEvent::Connected);
+        while let Some(message) = subscriber.next().await {
+          if inbox.send(message).is_err() {
+            return;
+          }
+        }
+        let _ = ready.send(false);
+        "subscription ended".to_string()
+      },
+      Err(reason) => reason,
+    };
+    if backoff.attempt() == 0 {
+      events.record(NetworkEvent::Disconnected { reason: reason.clone() });
+    }
+    let delay = backoff.next_delay();
+    tracing::warn!("NATS subscription at {url} failed, retrying in {delay:?}: {reason}");
+    events.record(NetworkEvent::Reconnecting { attempt: backoff.attempt(), delay });
+    tokio::time::sleep(delay).await;
+  }
+}
+
+async fn subscribe_to(
+  client: &OnceCell<async_nats::Client>,
+  url: &str,
+  subject: &str,
+) -> Result<async_nats::Subscriber, String> {
+  let client = connect(client, url).await.map_err(|e| format!("failed to connect: {e}"))?;
+  client.subscribe(subject.to_string()).await.map_err(|e| format!("failed to subscribe: {e}"))
+}
+
+fn type_id_for(types: &RwLock<HashMap<u64, TypeId>>, subject: &str) -> Option<TypeId> {
+  let hash = subject.rsplit('.').next()?;
+  let hash = u64::from_str_radix(hash, 16).ok()?;
+  tagged_type(types, hash)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NatsAddress(u64);
+
+impl NatsAddress {
+  pub const fn from_u64(id: u64) -> Self { Self(id) }
+
+  pub const fn as_u64(&self) -> u64 { self.0 }
+}
+
+impl FrameAddress for NatsAddress {
+  fn to_wire(&self) -> u64 { self.0 }
+
+  fn from_wire(id: u64) -> Self { Self(id) }
+}
+
+impl Generateable for NatsAddress {
+  fn generate() -> Self {
+    use std::sync::atomic::AtomicU32;
+    static COUNTER: AtomicU32 = AtomicU32::new(1);
+    // The process id keeps addresses from colliding between processes sharing a server.
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    Self((u64::from(std::process::id()) << 32) | u64::from(id))
+  }
+}
+
+impl std::fmt::Display for NatsAddress {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "nats-{:016x}", self.0)
+  }
+}
+
+impl Drop for Nats {
+  fn drop(&mut self) { self.subscription.abort(); }
+}
+
+impl Network for Nats {
+  type Address = NatsAddress;
+  type Payload = Vec<u8>;
+
+  fn new() -> Self {
+    let url = std::env::var("ARBITER_NATS_URL").unwrap_or_else(|_| DEFAULT_NATS_URL.to_string());
+    Self::with_url(url, DEFAULT_SUBJECT_PREFIX)
+  }
+
+  fn join(&self) -> Self {
+    Self::subscribe(self.url.clone(), self.prefix.clone(), self.client.clone(), self.types.clone())
+  }
+
+  async fn send(&self, envelope: Envelope<Self>) {
+    let type_id = envelope.type_id;
+    self.types.write().unwrap().entry(type_hash(type_id)).or_insert(type_id);
+    let subject = self.subject(type_id);
+    match connect(&self.client, &self.url).await {
+      Ok(client) =>
+        if let Err(e) = client.publish(subject, frame::frame(&envelope).into()).await {
+          tracing::error!("failed to publish to NATS: {e}");
+          self.dropped.fetch_add(1, Ordering::Relaxed);
+        },
+      Err(e) => {
+        tracing::error!("failed to connect to NATS at {}: {e}", self.url);
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+      },
+    }
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    loop {
+      let message = self.inbox.recv().await?;
+      let Some(type_id) = type_id_for(&self.types, message.subject.as_str()) else {
+        tracing::debug!("dropping NATS message on unknown subject {}", message.subject);
+        continue;
+      };
+      match frame::unframe(&message.payload, type_id) {
+        Some(envelope) => return Some(envelope),
+        None => tracing::debug!("dropping malformed NATS message on {}", message.subject),
+      }
+    }
+  }
+
+  fn take_events(&mut self) -> Vec<NetworkEvent> { self.events.take() }
+
+  fn event_signal(&self) -> Option<Arc<Notify>> { Some(self.events.signal()) }
+
+  /// Messages that arrived but were not yet received count towards the queue depth, and envelopes
+  /// that could not be published count as dropped.
+  fn stats(&self) -> NetworkStats {
+    NetworkStats {
+      queue_depth: self.inbox.len(),
+      dropped: self.dropped.load(Ordering::Relaxed),
+      ..NetworkStats::default()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{NumberMessage, TextMessage};
+
+  #[test]
+  fn test_subjects_resolve_to_types() {
+    let types = RwLock::new(HashMap::new());
+    let type_id = TypeId::of::<TextMessage>();
+    types.write().unwrap().insert(type_hash(type_id), type_id);
+
+    let subject = format!("arbiter.{:016x}", type_hash(type_id));
+    assert_eq!(type_id_for(&types, &subject), Some(type_id));
+    let unknown = format!("arbiter.{:016x}", type_hash(TypeId::of::<NumberMessage>()));
+    assert_eq!(type_id_for(&types, &unknown), None);
+    assert_eq!(type_id_for(&types, "arbiter.not-hex"), None);
+    assert_eq!(type_id_for(&types, ""), None);
+  }
+}
@@ -0,0 +1,108 @@
+//! Helpers for transports that reconnect after transient failures.
+
+use std::{collections::VecDeque, time::Duration};
+
+/// Exponentially growing delays between reconnection attempts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+  /// The delay before the first retry.
+  pub initial:    Duration,
+  /// The longest delay between retries.
+  pub max:        Duration,
+  /// How much the delay grows after each failed attempt.
+  pub multiplier: f64,
+  attempt:        u32,
+}
+
+impl Backoff {
+  pub const fn new(initial: Duration, max: Duration, multiplier: f64) -> Self {
+    Self { initial, max, multiplier, attempt: 0 }
+  }
+
+  /// How many retries have been scheduled since the last [`Backoff::reset`].
+  pub const fn attempt(&self) -> u32 { self.attempt }
+
+  /// The delay before the next retry.
+  pub fn next_delay(&mut self) -> Duration {
+    let exponent = i32::try_from(self.attempt).unwrap_or(i32::MAX);
+    self.attempt = self.attempt.saturating_add(1);
+    let delay = self.initial.as_nanos() as f64 * self.multiplier.powi(exponent);
+    Duration::from_nanos(delay.min(self.max.as_nanos() as f64).round() as u64)
+  }
+
+  /// Starts over from the initial delay, once a connection has succeeded.
+  pub fn reset(&mut self) { self.attempt = 0; }
+}
+
+impl Default for Backoff {
+  /// Starts at 100ms and doubles up to 30s.
+  fn default() -> Self { Self::new(Duration::from_millis(100), Duration::from_secs(30), 2.0) }
+}
+
+/// The largest number of items a [`ResendQueue`] holds by default.
+pub const DEFAULT_RESEND_CAPACITY: usize = 1024;
+
+/// Outbound items that could not be sent while disconnected, held until the transport reconnects.
+///
+/// The queue is bounded: once full, the oldest item is discarded to make room and counted.
+#[derive(Debug)]
+pub struct ResendQueue<T> {
+  items:     VecDeque<T>,
+  capacity:  usize,
+  discarded: u64,
+}
+
+impl<T> ResendQueue<T> {
+  pub fn new(capacity: usize) -> Self {
+    Self { items: VecDeque::new(), capacity: capacity.max(1), discarded: 0 }
+  }
+
+  pub fn push(&mut self, item: T) {
+    if self.items.len() == self.capacity {
+      self.items.pop_front();
+      self.discarded += 1;
+    }
+    self.items.push_back(item);
+  }
+
+  /// Takes every queued item, oldest first.
+  pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ { self.items.drain(..) }
+
+  pub fn len(&self) -> usize { self.items.len() }
+
+  pub fn is_empty(&self) -> bool { self.items.is_empty() }
+
+  /// How many items have been discarded because the queue was full.
+  pub const fn discarded(&self) -> u64 { self.discarded }
+}
+
+impl<T> Default for ResendQueue<T> {
+  fn default() -> Self { Self::new(DEFAULT_RESEND_CAPACITY) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_backoff_grows_to_max_and_resets() {
+    let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(350), 2.0);
+    assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    assert_eq!(backoff.next_delay(), Duration::from_millis(200));
+    assert_eq!(backoff.next_delay(), Duration::from_millis(350));
+    assert_eq!(backoff.attempt(), 3);
+    backoff.reset();
+    assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+  }
+
+  #[test]
+  fn test_resend_queue_discards_oldest() {
+    let mut queue = ResendQueue::new(2);
+    queue.push(1);
+    queue.push(2);
+    queue.push(3);
+    assert_eq!(queue.discarded(), 1);
+    assert_eq!(queue.drain().collect::<Vec<_>>(), [2, 3]);
+    assert!(queue.is_empty());
+  }
+}
@@ -0,0 +1,440 @@
+//! Recording the traffic of a byte network and replaying it into a fresh one.
+//!
+//! [`Recorded`] wraps a network whose payloads are bytes and writes every envelope sent or received
+//! through it, or through any connection joined from it, to a shared [`Recorder`] as one JSON
+//! line: whether it was sent or received, when, counted from the start of the recording, the
+//! sender's and recipients' addresses, the message type's tag, and the payload in hex. Lines are
+//! written by a background thread, so recording never blocks the connection; [`Recorder::flush`]
+//! waits until everything recorded so far is written. A [`Replay`] reads a recording back and sends
+//! the same envelopes into another network at the same offsets, so the traffic of a run that went
+//! wrong can be fed to a new set of agents until the bug is found. Only sent envelopes are
+//! replayed, since the received ones are what those sends produced. Under a paused tokio clock the
+//! replay is deterministic.
+//!
+//! Message types are recorded by their [tags](super::tags), and are resolved on replay through
+//! [`Replay::with_message`] or the registered tags. Types without a registered tag can only be
+//! replayed by the build that recorded them. Addresses are recorded with `Display` and mapped to
+//! addresses on the replay network with [`Replay::with_address`]. Envelopes from unmapped senders
+//! are replayed without one, and unmapped recipients are left out.
+
+use std::{
+  any::TypeId,
+  collections::HashMap,
+  fmt::{Debug, Write as _},
+  fs::File,
+  io::{self, BufRead, BufReader, LineWriter, Write},
+  path::Path,
+  sync::{mpsc, Arc},
+  time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::{
+  handler::{Delivery, Envelope, Message},
+  network::{tags::TypeTags, type_hash, Network, NetworkEvent, NetworkStats},
+};
+
+/// The environment variable [`Network::new`] reads the recording's path from.
+pub const PATH_VARIABLE: &str = "ARBITER_RECORDING";
+
+/// Whether a recorded envelope was sent or received.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+  #[default]
+  Sent,
+  Received,
+}
+
+/// One recorded envelope.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+  /// Whether the envelope was sent or received.
+  #[serde(default)]
+  pub direction: Direction,
+  /// Microseconds from the start of the recording to when the envelope was sent or received.
+  pub at_micros: u64,
+  /// The sender's address, if the envelope named one.
+  pub from:      Option<String>,
+  /// The recipients' addresses, or nothing for a broadcast.
+  #[serde(default)]
+  pub to:        Option<Vec<String>>,
+  /// The message type's tag, as 16 hex characters.
+  pub type_tag:  String,
+  /// The payload, in hex.
+  pub payload:   String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplayError {
+  #[error("failed to read the recording: {0}")]
+  Io(#[from] io::Error),
+  #[error("line {line} of the recording is malformed: {source}")]
+  Malformed { line: usize, source: serde_json::Error },
+}
+
+/// Where a [`Recorded`] network writes its envelopes.
+pub struct Recorder {
+  started:  Instant,
+  commands: mpsc::Sender<Command>,
+}
+
+/// What the writer thread of a [`Recorder`] is asked to do.
+enum Command {
+  Write(Vec<u8>),
+  Flush(oneshot::Sender<()>),
+}
+
+impl Debug for Recorder {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Recorder").field("started", &self.started).finish_non_exhaustive()
+  }
+}
+
+impl Recorder {
+  /// Starts a recording in a new file at `path`, replacing any file already there.
+  pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+    Ok(Self::with_writer(LineWriter::new(File::create(path)?)))
+  }
+
+  /// Starts a recording written to `output` from a background thread.
+  ///
+  /// # Panics
+  ///
+  /// If the thread cannot be spawned.
+  pub fn with_writer(output: impl Write + Send + 'static) -> Self {
+    let (commands, received) = mpsc::channel();
+    std::thread::Builder::new()
+      .name("arbiter-recorder".to_string())
+      .spawn(move || write_records(output, &received))
+      .expect("failed to spawn the recording thread");
+    Self { started: Instant::now(), commands }
+  }
+
+  /// Waits until everything recorded so far has been written and the output flushed.
+  pub async fn flush(&self) {
+    let (done, written) = oneshot::channel();
+    if self.commands.send(Command::Flush(done)).is_ok() {
+      let _ = written.await;
+    }
+  }
+
+  fn record<N: Network<Payload = Vec<u8>>>(&self, direction: Direction, envelope: &Envelope<N>) {
+    let to = match &envelope.to {
+      Delivery::Broadcast => None,
+      Delivery::Unicast(to) => Some(vec![to.to_string()]),
+      Delivery::Multicast(to) => Some(to.iter().map(ToString::to_string).collect()),
+    };
+    let record = Record {
+      direction,
+      at_micros: u64::try_from(self.started.elapsed().as_micros()).unwrap_or(u64::MAX),
+      from: envelope.from.map(|from| from.to_string()),
+      to,
+      type_tag: format!("{:016x}", type_hash(envelope.type_id)),
+      payload: encode_hex(&envelope.payload),
+    };
+    let mut line = serde_json::to_vec(&record).unwrap();
+    line.push(b'\n');
+    if self.commands.send(Command::Write(line)).is_err() {
+      tracing::error!("the recording thread has stopped, dropping a record");
+    }
+  }
+}
+
+/// Writes lines to `output` until the [`Recorder`] is dropped.
+fn write_records(mut output: impl Write, commands: &mpsc::Receiver<Command>) {
+  for command in commands {
+    match command {
+      Command::Write(line) =>
+        if let Err(e) = output.write_all(&line) {
+          tracing::error!("failed to record envelope: {e}");
+        },
+      Command::Flush(done) => {
+        if let Err(e) = output.flush() {
+          tracing::error!("failed to flush the recording: {e}");
+        }
+        let _ = done.send(());
+      },
+    }
+  }
+  let _ = output.flush();
+}
+
+pub struct Recorded<N: Network<Payload = Vec<u8>>> {
+  inner:    N,
+  address:  Option<N::Address>,
+  recorder: Arc<Recorder>,
+}
+
+impl<N: Network<Payload = Vec<u8>> + Debug> Debug for Recorded<N> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Recorded")
+      .field("inner", &self.inner)
+      .field("address", &self.address)
+      .finish_non_exhaustive()
+  }
+}
+
+impl<N: Network<Payload = Vec<u8>>> Recorded<N> {
+  /// Wraps `inner`, recording to `recorder` from this network and every network joined from it.
+  pub fn with_recorder(inner: N, recorder: Arc<Recorder>) -> Self {
+    Self { inner, address: None, recorder }
+  }
+
+  pub fn recorder(&self) -> &Arc<Recorder> { &self.recorder }
+
+  pub const fn inner(&self) -> &N { &self.inner }
+}
+
+impl<N: Network<Payload = Vec<u8>>> Network for Recorded<N> {
+  type Address = N::Address;
+  type Payload = Vec<u8>;
+
+  /// Wraps a new `N`, recording to the file named by [`PATH_VARIABLE`].
+  ///
+  /// # Panics
+  ///
+  /// If the variable is unset or the file cannot be created.
+  fn new() -> Self {
+    let path = std::env::var(PATH_VARIABLE)
+      .unwrap_or_else(|_| panic!("{PATH_VARIABLE} must be set to the recording's path"));
+    let recorder = Recorder::create(&path)
+      .unwrap_or_else(|e| panic!("failed to create the recording at {path}: {e}"));
+    Self::with_recorder(N::new(), Arc::new(recorder))
+  }
+
+  fn join(&self) -> Self { Self::with_recorder(self.inner.join(), self.recorder.clone()) }
+
+  fn bind(&mut self, address: Self::Address) {
+    self.address = Some(address);
+    self.inner.bind(address);
+  }
+
+  async fn send(&self, mut envelope: Envelope<Self>) {
+    envelope.from = envelope.from.or(self.address);
+    self.recorder.record(Direction::Sent, &envelope);
+    self.inner.send(envelope.map_payload(std::convert::identity)).await;
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    let envelope = self.inner.receive().await?.map_payload(std::convert::identity);
+    self.recorder.record(Direction::Received, &envelope);
+    Some(envelope)
+  }
+
+  fn take_events(&mut self) -> Vec<NetworkEvent> { self.inner.take_events() }
+
+  fn event_signal(&self) -> Option<std::sync::Arc<tokio::sync::Notify>> {
+    self.inner.event_signal()
+  }
+
+  /// Also waits until the recording has caught up.
+  async fn flush(&self) {
+    self.inner.flush().await;
+    self.recorder.flush().await;
+  }
+
+  fn stats(&self) -> NetworkStats { self.inner.stats() }
+}
+
+/// A recording read back for replay.
+#[derive(Debug, Clone)]
+pub struct Replay<A> {
+  records:   Vec<Record>,
+  types:     HashMap<u64, TypeId>,
+  addresses: HashMap<String, A>,
+}
+
+impl<A: Copy> Replay<A> {
+  /// Reads the recording at `path`.
+  pub fn open(path: impl AsRef<Path>) -> Result<Self, ReplayError> {
+    Self::from_reader(BufReader::new(File::open(path)?))
+  }
+
+  /// Reads a recording from `reader`, one record per line.
+  pub fn from_reader(reader: impl BufRead) -> Result<Self, ReplayError> {
+    let mut records = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+      let line = line?;
+      if line.trim().is_empty() {
+        continue;
+      }
+      let record = serde_json::from_str(&line);
+      records.push(record.map_err(|source| ReplayError::Malformed { line: i + 1, source })?);
+    }
+    Ok(Self { records, types: HashMap::new(), addresses: HashMap::new() })
+  }
+
+  /// Replays recorded envelopes of type `M`. Envelopes of types that were not registered are
+  /// skipped.
+  pub fn with_message<M: Message>(mut self) -> Self {
+    let type_id = TypeId::of::<M>();
+    self.types.insert(type_hash(type_id), type_id);
+    self
+  }
+
+  /// Replays envelopes recorded as sent from `recorded` as sent from `address`.
+  pub fn with_address(mut self, recorded: impl Into<String>, address: A) -> Self {
+    self.addresses.insert(recorded.into(), address);
+    self
+  }
+
+  pub fn records(&self) -> &[Record] { &self.records }
+
+  /// Sends every replayable envelope that was recorded as sent into `network`, each at the offset
+  /// from now at which it was recorded. Returns how many were sent.
+  pub async fn run<N>(&self, network: &N) -> usize
+  where N: Network<Address = A, Payload = Vec<u8>> {
+    let started = Instant::now();
+    let mut replayed = 0;
+    for record in self.records.iter().filter(|record| record.direction == Direction::Sent) {
+      let Some(envelope) = self.resolve::<N>(record) else {
+        tracing::warn!("skipping recorded envelope with unknown tag {}", record.type_tag);
+        continue;
+      };
+      tokio::time::sleep_until(started + Duration::from_micros(record.at_micros)).await;
+      network.send(envelope).await;
+      replayed += 1;
+    }
+    replayed
+  }
+
+  fn resolve<N>(&self, record: &Record) -> Option<Envelope<N>>
+  where N: Network<Address = A, Payload = Vec<u8>> {
+    let hash = u64::from_str_radix(&record.type_tag, 16).ok()?;
+    let type_id = self.types.get(&hash).copied().or_else(|| TypeTags::global().type_of(hash))?;
+    let mut envelope = Envelope::new(decode_hex(&record.payload)?, type_id);
+    envelope.from = record.from.as_ref().and_then(|from| self.addresses.get(from).copied());
+    if let Some(to) = &record.to {
+      let mut to: Vec<_> = to.iter().filter_map(|to| self.addresses.get(to).copied()).collect();
+      envelope.to = match to.len() {
+        1 => Delivery::Unicast(to.remove(0)),
+        _ => Delivery::Multicast(to),
+      };
+    }
+    Some(envelope)
+  }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+  let mut hex = String::with_capacity(2 * bytes.len());
+  for byte in bytes {
+    let _ = write!(hex, "{byte:02x}");
+  }
+  hex
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+  if hex.len() % 2 != 0 {
+    return None;
+  }
+  (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Mutex;
+
+  use super::*;
+  use crate::{
+    fixtures::{NumberMessage, TextMessage, Wire, WireAddress},
+    network::Generateable,
+  };
+
+  #[derive(Clone, Default)]
+  struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for Buffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().extend_from_slice(bytes);
+      Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+  }
+
+  #[tokio::test]
+  async fn test_recording_reads_back_for_replay() {
+    let buffer = Buffer::default();
+    let recorder = Recorder::with_writer(buffer.clone());
+    let (alice, bob) = (WireAddress::generate(), WireAddress::generate());
+    let text = br#"{"content":"hi"}"#;
+    let mut envelope = Envelope::<Wire>::new(text.to_vec(), TypeId::of::<TextMessage>());
+    envelope.from = Some(alice);
+    envelope.to = Delivery::Unicast(bob);
+    recorder.record(Direction::Sent, &envelope);
+    let envelope = Envelope::<Wire>::new(vec![0, 255], TypeId::of::<NumberMessage>());
+    recorder.record(Direction::Received, &envelope);
+    recorder.flush().await;
+
+    let recording = buffer.0.lock().unwrap().clone();
+    let replay = Replay::from_reader(recording.as_slice())
+      .unwrap()
+      .with_message::<TextMessage>()
+      .with_address(alice.to_string(), WireAddress::generate());
+    assert_eq!(replay.records().len(), 2);
+    assert_eq!(replay.records()[1].direction, Direction::Received);
+    assert!(replay.records()[0].at_micros <= replay.records()[1].at_micros);
+
+    let envelope = replay.resolve::<Wire>(&replay.records()[0]).unwrap();
+    assert_eq!(envelope.type_id, TypeId::of::<TextMessage>());
+    assert_eq!(envelope.payload, text);
+    assert_eq!(envelope.from, replay.addresses.get(&alice.to_string()).copied());
+    // Bob has no address on the replay network.
+    assert_eq!(envelope.to, Delivery::Multicast(Vec::new()));
+    // `NumberMessage` was not registered for replay.
+    assert!(replay.resolve::<Wire>(&replay.records()[1]).is_none());
+    assert_eq!(decode_hex(&replay.records()[1].payload), Some(vec![0, 255]));
+
+    let malformed = Replay::<u8>::from_reader(&b"{}\n"[..]);
+    assert!(matches!(malformed, Err(ReplayError::Malformed { line: 1, .. })));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_recorded_traffic_replays_on_schedule() {
+    let buffer = Buffer::default();
+    let network =
+      Recorded::with_recorder(Wire::new(), Arc::new(Recorder::with_writer(buffer.clone())));
+    let (alice, bob) = (WireAddress::generate(), WireAddress::generate());
+    let mut sender = network.join();
+    sender.bind(alice);
+    let type_id = TypeId::of::<TextMessage>();
+    sender.send(Envelope::new(b"first".to_vec(), type_id)).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut second = Envelope::new(b"second".to_vec(), type_id);
+    second.to = Delivery::Unicast(bob);
+    sender.send(second).await;
+    assert_eq!(sender.receive().await.unwrap().payload, b"first");
+    sender.flush().await;
+
+    let recording = buffer.0.lock().unwrap().clone();
+    let directions: Vec<_> = Replay::<WireAddress>::from_reader(recording.as_slice())
+      .unwrap()
+      .records()
+      .iter()
+      .map(|record| record.direction)
+      .collect();
+    assert_eq!(directions, [Direction::Sent, Direction::Sent, Direction::Received]);
+
+    let (carol, dave) = (WireAddress::generate(), WireAddress::generate());
+    let replay = Replay::from_reader(recording.as_slice())
+      .unwrap()
+      .with_message::<TextMessage>()
+      .with_address(alice.to_string(), carol)
+      .with_address(bob.to_string(), dave);
+    let target = Wire::new();
+    let mut listener = target.join();
+    let started = Instant::now();
+    assert_eq!(replay.run(&target).await, 2);
+    assert_eq!(started.elapsed(), Duration::from_millis(100));
+
+    let first = listener.receive().await.unwrap();
+    assert_eq!((first.payload, first.from), (b"first".to_vec(), Some(carol)));
+    let second = listener.receive().await.unwrap();
+    assert_eq!((second.payload, second.from), (b"second".to_vec(), Some(carol)));
+    let resent = replay.resolve::<Wire>(&replay.records()[1]).unwrap();
+    assert_eq!(resent.to, Delivery::Unicast(dave));
+  }
+}
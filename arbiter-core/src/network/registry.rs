@@ -0,0 +1,199 @@
+//! Name and capability lookup for network addresses.
+//!
+//! A [`Registry`] maps human-readable names to addresses, one name per address, and lets any
+//! number of addresses advertise capability tags. It is generic over the address type, so it works
+//! the same for every [`Network`](super::Network). Clones share the same table, so one registry can
+//! be handed to every agent in a simulation.
+//!
+//! [`Registry::send_to_name`] addresses an envelope to whoever is registered under a name. An agent
+//! registered with [`Agent::register_as`](crate::agent::Agent::register_as) is removed from the
+//! registry when it stops.
+
+use std::{
+  collections::{HashMap, HashSet},
+  hash::Hash,
+  sync::{Arc, RwLock},
+};
+
+use crate::{
+  handler::{Delivery, Envelope},
+  network::Network,
+};
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum RegistryError {
+  #[error("the name `{0}` is already registered to another address")]
+  NameTaken(String),
+  #[error("the address is already registered as `{0}`")]
+  AddressTaken(String),
+  #[error("no address is registered as `{0}`")]
+  UnknownName(String),
+}
+
+#[derive(Debug)]
+struct Table<A> {
+  addresses: HashMap<String, A>,
+  names:     HashMap<A, String>,
+  tags:      HashMap<String, HashSet<A>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Registry<A> {
+  table: Arc<RwLock<Table<A>>>,
+}
+
+impl<A: Copy + Eq + Hash> Default for Registry<A> {
+  fn default() -> Self {
+    Self {
+      table: Arc::new(RwLock::new(Table {
+        addresses: HashMap::new(),
+        names:     HashMap::new(),
+        tags:      HashMap::new(),
+      })),
+    }
+  }
+}
+
+impl<A: Copy + Eq + Hash> Registry<A> {
+  pub fn new() -> Self { Self::default() }
+
+  /// Registers `address` under `name`. Registering the same pair twice is a no-op.
+  pub fn register(&self, name: impl Into<String>, address: A) -> Result<(), RegistryError> {
+    let name = name.into();
+    let mut table = self.table.write().unwrap();
+    match (table.addresses.get(&name), table.names.get(&address)) {
+      (Some(existing), _) if *existing == address => return Ok(()),
+      (Some(_), _) => return Err(RegistryError::NameTaken(name)),
+      (None, Some(existing)) => return Err(RegistryError::AddressTaken(existing.clone())),
+      (None, None) => {},
+    }
+    table.addresses.insert(name.clone(), address);
+    table.names.insert(address, name);
+    Ok(())
+  }
+
+  /// Removes `address` from the registry, along with its name and all of its tags.
+  pub fn unregister(&self, address: A) {
+    let mut table = self.table.write().unwrap();
+    if let Some(name) = table.names.remove(&address) {
+      table.addresses.remove(&name);
+    }
+    table.tags.retain(|_, addresses| {
+      addresses.remove(&address);
+      !addresses.is_empty()
+    });
+  }
+
+  /// The address registered under `name`.
+  pub fn resolve(&self, name: &str) -> Option<A> {
+    self.table.read().unwrap().addresses.get(name).copied()
+  }
+
+  /// The name `address` is registered under.
+  pub fn name_of(&self, address: A) -> Option<String> {
+    self.table.read().unwrap().names.get(&address).cloned()
+  }
+
+  /// Sends `envelope` over `network` to the address registered under `name` only.
+  pub async fn send_to_name<N>(
+    &self,
+    network: &N,
+    name: &str,
+    envelope: Envelope<N>,
+  ) -> Result<(), RegistryError>
+  where
+    N: Network<Address = A>,
+  {
+    let address = self.resolve(name).ok_or_else(|| RegistryError::UnknownName(name.to_string()))?;
+    network.send(envelope.with_delivery(Delivery::Unicast(address))).await;
+    Ok(())
+  }
+
+  /// Advertises that `address` provides `tag`.
+  pub fn tag(&self, address: A, tag: impl Into<String>) {
+    self.table.write().unwrap().tags.entry(tag.into()).or_default().insert(address);
+  }
+
+  /// Withdraws `tag` from `address`.
+  pub fn untag(&self, address: A, tag: &str) {
+    let mut table = self.table.write().unwrap();
+    if let Some(addresses) = table.tags.get_mut(tag) {
+      addresses.remove(&address);
+      if addresses.is_empty() {
+        table.tags.remove(tag);
+      }
+    }
+  }
+
+  /// Every address advertising `tag`, in no particular order.
+  pub fn tagged(&self, tag: &str) -> Vec<A> {
+    let table = self.table.read().unwrap();
+    table.tags.get(tag).map(|addresses| addresses.iter().copied().collect()).unwrap_or_default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    fixtures::TextMessage,
+    network::{
+      memory::{InMemory, InMemoryAddress},
+      Generateable,
+    },
+  };
+
+  #[test]
+  fn test_register_and_resolve() {
+    let registry = Registry::<u64>::new();
+    registry.register("alice", 1).unwrap();
+    registry.register("alice", 1).unwrap();
+    assert_eq!(registry.register("alice", 2), Err(RegistryError::NameTaken("alice".to_string())));
+    assert_eq!(registry.register("bob", 1), Err(RegistryError::AddressTaken("alice".to_string())));
+
+    assert_eq!(registry.resolve("alice"), Some(1));
+    assert_eq!(registry.name_of(1), Some("alice".to_string()));
+
+    registry.unregister(1);
+    assert_eq!(registry.resolve("alice"), None);
+    registry.register("bob", 1).unwrap();
+  }
+
+  #[test]
+  fn test_tags() {
+    let registry = Registry::<u64>::new();
+    registry.tag(1, "market-maker");
+    registry.tag(2, "market-maker");
+    let mut tagged = registry.tagged("market-maker");
+    tagged.sort_unstable();
+    assert_eq!(tagged, vec![1, 2]);
+
+    registry.untag(1, "market-maker");
+    registry.unregister(2);
+    assert!(registry.tagged("market-maker").is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_send_to_name() {
+    let network = InMemory::new();
+    let (alice, bob) = (InMemoryAddress::generate(), InMemoryAddress::generate());
+    let mut to_alice = network.join();
+    to_alice.bind(alice);
+    let mut to_bob = network.join();
+    to_bob.bind(bob);
+    let registry = Registry::new();
+    registry.register("alice", alice).unwrap();
+
+    let envelope = Envelope::package(TextMessage { content: "Hello".to_string() });
+    registry.send_to_name(&network, "alice", envelope).await.unwrap();
+    let received = to_alice.receive().await.unwrap();
+    assert_eq!(received.to, Delivery::Unicast(alice));
+    assert!(to_bob.mailbox.as_mut().unwrap().1.try_recv().is_err());
+
+    let envelope = Envelope::package(TextMessage { content: "Hello".to_string() });
+    assert_eq!(
+      registry.send_to_name(&network, "carol", envelope).await,
+      Err(RegistryError::UnknownName("carol".to_string()))
+    );
+  }
+}
@@ -0,0 +1,379 @@
+//! A [`Network`] decorator that numbers byte envelopes so receivers can drop duplicates and restore
+//! the order they were sent in.
+//!
+//! Lossy links reorder traffic, and reconnecting transports resend what they could not confirm was
+//! delivered, so the same envelope can arrive twice, or after one that was sent later.
+//! [`Sequenced`] prefixes every payload with a random id for the sending connection and the number
+//! of envelopes that connection sent before it. Receivers track every sender separately and apply
+//! the [`Guarantee`] in their [`SequenceConfig`]:
+//! - [`Guarantee::Unordered`] delivers everything as it arrives.
+//! - [`Guarantee::Deduplicated`] drops envelopes that were already delivered.
+//! - [`Guarantee::Ordered`] also holds back envelopes that arrive ahead of a gap until the gap is
+//!   filled.
+//!
+//! A gap that is not filled within [`SequenceConfig::max_wait`], or that leaves more than
+//! [`SequenceConfig::window`] envelopes waiting, is given up on. The envelopes behind it are
+//! delivered, and the missing ones are reported as a [`NetworkEvent::Lagged`]. Receivers expect
+//! every sender from its first envelope, so what a sender sent before a receiver joined is reported
+//! the same way. Every peer on the underlying network must be sequenced too, since payloads without
+//! a header are dropped.
+
+use std::{
+  collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+  fmt::Debug,
+  hash::{BuildHasher, Hash, Hasher},
+  sync::atomic::{AtomicU64, Ordering},
+  time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+  handler::Envelope,
+  network::{Network, NetworkEvent, NetworkStats},
+};
+
+const HEADER_LEN: usize = 16;
+
+/// What a receiving connection promises about the envelopes it hands over from each sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Guarantee {
+  /// Deliver envelopes as they arrive, duplicates included.
+  Unordered,
+  /// Deliver each envelope at most once, in the order they arrive.
+  Deduplicated,
+  /// Deliver each envelope at most once, in the order they were sent.
+  Ordered,
+}
+
+/// How [`Sequenced`] orders and deduplicates what it receives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceConfig {
+  pub guarantee: Guarantee,
+  /// How many envelopes past a gap are remembered or held back before the gap is given up on.
+  pub window:    usize,
+  /// How long [`Guarantee::Ordered`] waits for a gap to be filled before giving up on it.
+  pub max_wait:  Duration,
+}
+
+impl Default for SequenceConfig {
+  fn default() -> Self {
+    Self { guarantee: Guarantee::Ordered, window: 1024, max_wait: Duration::from_millis(100) }
+  }
+}
+
+/// What has been delivered from one sender.
+#[derive(Debug)]
+struct Stream<T> {
+  /// The lowest sequence number not yet delivered or given up on.
+  next:          u64,
+  /// Sequence numbers past `next` that were already delivered.
+  seen:          BTreeSet<u64>,
+  /// Envelopes past `next` waiting for the gap before them to be filled.
+  held:          BTreeMap<u64, T>,
+  /// When the stream last stopped making progress with envelopes held back.
+  stalled_since: Option<Instant>,
+}
+
+impl<T> Stream<T> {
+  const fn starting_at(next: u64) -> Self {
+    Self { next, seen: BTreeSet::new(), held: BTreeMap::new(), stalled_since: None }
+  }
+
+  fn is_duplicate(&self, sequence: u64) -> bool {
+    sequence < self.next || self.seen.contains(&sequence) || self.held.contains_key(&sequence)
+  }
+
+  /// Moves `next` past everything delivered, and everything held, that follows it without a gap.
+  fn release(&mut self, ready: &mut VecDeque<T>, now: Instant) {
+    let before = self.next;
+    loop {
+      if !self.seen.remove(&self.next) {
+        let Some(item) = self.held.remove(&self.next) else { break };
+        ready.push_back(item);
+      }
+      self.next += 1;
+    }
+    self.stalled_since = if self.held.is_empty() {
+      None
+    } else if self.next > before {
+      Some(now)
+    } else {
+      self.stalled_since.or(Some(now))
+    };
+  }
+
+  /// Gives up on the gap at `next`, returning how many sequence numbers were skipped.
+  fn skip_gap(&mut self, ready: &mut VecDeque<T>, now: Instant) -> u64 {
+    let first = self.seen.first().into_iter().chain(self.held.keys()).min().copied();
+    let Some(first) = first else { return 0 };
+    let skipped = first - self.next;
+    self.next = first;
+    self.release(ready, now);
+    skipped
+  }
+}
+
+/// The per-sender state of a receiving connection.
+#[derive(Debug)]
+struct Streams<T> {
+  config:     SequenceConfig,
+  streams:    HashMap<u64, Stream<T>>,
+  ready:      VecDeque<T>,
+  duplicates: u64,
+}
+
+impl<T> Streams<T> {
+  fn new(config: SequenceConfig) -> Self {
+    Self { config, streams: HashMap::new(), ready: VecDeque::new(), duplicates: 0 }
+  }
+
+  /// Takes in envelope `sequence` from `sender`, returning how many envelopes were given up on to
+  /// make room for it.
+  fn accept(&mut self, sender: u64, sequence: u64, item: T, now: Instant) -> u64 {
+    if self.config.guarantee == Guarantee::Unordered {
+      self.ready.push_back(item);
+      return 0;
+    }
+    // Every sender counts from zero, so envelopes it sent before the first one to arrive are
+    // waited for like any other gap.
+    let stream = self.streams.entry(sender).or_insert_with(|| Stream::starting_at(0));
+    if stream.is_duplicate(sequence) {
+      self.duplicates += 1;
+      return 0;
+    }
+    match self.config.guarantee {
+      Guarantee::Ordered => {
+        stream.held.insert(sequence, item);
+      },
+      _ => {
+        self.ready.push_back(item);
+        stream.seen.insert(sequence);
+      },
+    }
+    stream.release(&mut self.ready, now);
+    let mut skipped = 0;
+    while stream.seen.len() + stream.held.len() > self.config.window {
+      skipped += stream.skip_gap(&mut self.ready, now);
+    }
+    skipped
+  }
+
+  /// Gives up on every gap that has been waiting longer than `max_wait`.
+  fn expire(&mut self, now: Instant) -> u64 {
+    let max_wait = self.config.max_wait;
+    let mut skipped = 0;
+    for stream in self.streams.values_mut() {
+      while stream.stalled_since.is_some_and(|since| since + max_wait <= now) {
+        skipped += stream.skip_gap(&mut self.ready, now);
+      }
+    }
+    skipped
+  }
+
+  /// When the longest-waiting gap will be given up on.
+  fn deadline(&self) -> Option<Instant> {
+    let stalled = self.streams.values().filter_map(|stream| stream.stalled_since).min()?;
+    Some(stalled + self.config.max_wait)
+  }
+}
+
+pub struct Sequenced<N: Network<Payload = Vec<u8>>> {
+  inner:    N,
+  config:   SequenceConfig,
+  id:       u64,
+  sequence: AtomicU64,
+  streams:  Streams<Envelope<N>>,
+  events:   Vec<NetworkEvent>,
+  lagged:   u64,
+}
+
+impl<N: Network<Payload = Vec<u8>> + Debug> Debug for Sequenced<N> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Sequenced")
+      .field("inner", &self.inner)
+      .field("config", &self.config)
+      .field("id", &self.id)
+      .finish_non_exhaustive()
+  }
+}
+
+impl<N: Network<Payload = Vec<u8>>> Sequenced<N> {
+  /// Wraps `inner`, sequencing with `config` on this network and every network joined from it.
+  pub fn with_config(inner: N, config: SequenceConfig) -> Self {
+    static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+    let connection = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    // Random, so that connections in different processes do not share an id.
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    (std::process::id(), connection).hash(&mut hasher);
+    let id = hasher.finish();
+    Self {
+      inner,
+      config,
+      id,
+      sequence: AtomicU64::new(0),
+      streams: Streams::new(config),
+      events: Vec::new(),
+      lagged: 0,
+    }
+  }
+
+  pub const fn config(&self) -> SequenceConfig { self.config }
+
+  pub const fn inner(&self) -> &N { &self.inner }
+
+  fn record_skipped(&mut self, skipped: u64) {
+    if skipped > 0 {
+      tracing::warn!("gave up waiting for {skipped} sequenced envelopes");
+      self.lagged += skipped;
+      self.events.push(NetworkEvent::Lagged { skipped });
+    }
+  }
+}
+
+impl<N: Network<Payload = Vec<u8>>> Network for Sequenced<N> {
+  type Address = N::Address;
+  type Payload = Vec<u8>;
+
+  fn new() -> Self { Self::with_config(N::new(), SequenceConfig::default()) }
+
+  fn join(&self) -> Self { Self::with_config(self.inner.join(), self.config) }
+
+  fn bind(&mut self, address: Self::Address) { self.inner.bind(address); }
+
+  async fn send(&self, envelope: Envelope<Self>) {
+    let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+    let header = [self.id.to_be_bytes(), sequence.to_be_bytes()].concat();
+    self.inner.send(envelope.map_payload(|payload| [header, payload].concat())).await;
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    loop {
+      if let Some(envelope) = self.streams.ready.pop_front() {
+        return Some(envelope.map_payload(std::convert::identity));
+      }
+      let deadline = self.streams.deadline();
+      let expire_at = deadline.unwrap_or_else(Instant::now);
+      tokio::select! {
+        envelope = self.inner.receive() => {
+          let envelope = envelope?;
+          if envelope.payload.len() < HEADER_LEN {
+            tracing::debug!("dropping envelope without a sequence header");
+            continue;
+          }
+          let (header, payload) = envelope.payload.split_at(HEADER_LEN);
+          let sender = u64::from_be_bytes(header[..8].try_into().unwrap());
+          let sequence = u64::from_be_bytes(header[8..].try_into().unwrap());
+          let payload = payload.to_vec();
+          let envelope = envelope.map_payload(|_| payload);
+          let skipped = self.streams.accept(sender, sequence, envelope, Instant::now());
+          self.record_skipped(skipped);
+        },
+        () = tokio::time::sleep_until(expire_at), if deadline.is_some() => {
+          let skipped = self.streams.expire(Instant::now());
+          self.record_skipped(skipped);
+        },
+      }
+    }
+  }
+
+  fn take_events(&mut self) -> Vec<NetworkEvent> {
+    let mut events = self.inner.take_events();
+    events.append(&mut self.events);
+    events
+  }
+
+  fn event_signal(&self) -> Option<std::sync::Arc<tokio::sync::Notify>> {
+    self.inner.event_signal()
+  }
+
+  async fn flush(&self) { self.inner.flush().await; }
+
+  /// Envelopes held back for ordering count towards the queue depth, duplicates count as dropped,
+  /// and envelopes given up on count as lagged.
+  fn stats(&self) -> NetworkStats {
+    let inner = self.inner.stats();
+    let held: usize = self.streams.streams.values().map(|stream| stream.held.len()).sum();
+    NetworkStats {
+      queue_depth: inner.queue_depth + held + self.streams.ready.len(),
+      dropped: inner.dropped + self.streams.duplicates,
+      lagged: inner.lagged + self.lagged,
+      ..inner
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::any::TypeId;
+
+  use super::*;
+  use crate::fixtures::Wire;
+
+  fn config(guarantee: Guarantee) -> SequenceConfig {
+    SequenceConfig { guarantee, window: 4, max_wait: Duration::from_millis(100) }
+  }
+
+  fn deliver(streams: &mut Streams<u64>, sequences: &[u64], now: Instant) -> Vec<u64> {
+    for &sequence in sequences {
+      streams.accept(1, sequence, sequence, now);
+    }
+    streams.ready.drain(..).collect()
+  }
+
+  #[test]
+  fn test_ordered_streams_hold_back_and_deduplicate() {
+    let now = Instant::now();
+    let mut streams = Streams::new(config(Guarantee::Ordered));
+    assert_eq!(deliver(&mut streams, &[0, 2, 3, 2, 0], now), [0]);
+    assert_eq!(deliver(&mut streams, &[1], now), [1, 2, 3]);
+    assert_eq!(streams.duplicates, 2);
+
+    // A gap that is never filled is given up on once `max_wait` has passed.
+    assert!(deliver(&mut streams, &[5, 6], now).is_empty());
+    assert_eq!(streams.deadline(), Some(now + Duration::from_millis(100)));
+    assert_eq!(streams.expire(now + Duration::from_millis(99)), 0);
+    assert_eq!(streams.expire(now + Duration::from_millis(100)), 1);
+    assert_eq!(streams.ready.drain(..).collect::<Vec<_>>(), [5, 6]);
+    assert_eq!(streams.deadline(), None);
+
+    // So is one that leaves more than `window` envelopes waiting.
+    let skipped: u64 = [8, 9, 10, 11, 12].into_iter().map(|s| streams.accept(1, s, s, now)).sum();
+    assert_eq!(skipped, 1);
+    assert_eq!(streams.ready.drain(..).collect::<Vec<_>>(), [8, 9, 10, 11, 12]);
+  }
+
+  #[tokio::test]
+  async fn test_sequenced_connections_reorder_what_they_receive() {
+    let wire = Wire::new();
+    let mut receiver = Sequenced::with_config(wire.join(), config(Guarantee::Ordered));
+    let raw = wire.join();
+    let type_id = TypeId::of::<u8>();
+    // The first envelope from a sender arrives after the second, then again.
+    for sequence in [1, 0, 1] {
+      let header = [7_u64.to_be_bytes(), sequence.to_be_bytes()].concat();
+      raw.send(Envelope::new([header, vec![sequence as u8]].concat(), type_id)).await;
+    }
+    raw.send(Envelope::new(vec![0; 3], type_id)).await;
+    let sender = Sequenced::with_config(wire.join(), config(Guarantee::Ordered));
+    sender.send(Envelope::new(vec![2], type_id)).await;
+
+    let mut payloads = Vec::new();
+    for _ in 0..3 {
+      payloads.push(receiver.receive().await.unwrap().payload);
+    }
+    assert_eq!(payloads, [[0], [1], [2]]);
+    assert_eq!(receiver.stats().dropped, 1);
+  }
+
+  #[test]
+  fn test_deduplicated_and_unordered_streams() {
+    let now = Instant::now();
+    let mut streams = Streams::new(config(Guarantee::Deduplicated));
+    assert_eq!(deliver(&mut streams, &[0, 2, 1, 2, 0, 3], now), [0, 2, 1, 3]);
+    assert_eq!(streams.duplicates, 2);
+
+    let mut streams = Streams::new(config(Guarantee::Unordered));
+    assert_eq!(deliver(&mut streams, &[0, 2, 0], now), [0, 2, 0]);
+  }
+}
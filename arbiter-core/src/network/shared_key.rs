@@ -0,0 +1,207 @@
+//! A [`Network`] decorator that encrypts and authenticates byte payloads with a shared key.
+//!
+//! [`Sealed`] seals every payload with ChaCha20-Poly1305 under a 32-byte key shared by everyone
+//! allowed on the network, with a fresh random nonce per envelope. The message type's tag and the
+//! envelope's [frame header](super::frame), with its sender, signature and recipients, are the
+//! associated data. Brokers and relays in between only ever see ciphertext, and envelopes that were
+//! not sealed with the same key, or whose payload, type, sender or recipients were altered on the
+//! way, are dropped on receive.
+//!
+//! This is a shared-key layer, not a secure channel like Noise or TLS:
+//! - There is no handshake. Peers never authenticate each other, and the key authenticates
+//!   membership of the network, not individual peers, so anyone holding it can send as anyone.
+//!   [`signing`](crate::signing) ties envelopes to their sender.
+//! - There is no replay protection. A sealed envelope captured on the wire is accepted again if it
+//!   is sent again, and messages whose effect must not repeat need their own sequence numbers.
+//! - There is no forward secrecy. Anyone who learns the key can open every envelope ever sealed
+//!   with it.
+//!
+//! Every peer on the underlying network must be sealed with the same key.
+
+use std::fmt::Debug;
+
+use chacha20poly1305::{
+  aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+  ChaCha20Poly1305, Key, Nonce,
+};
+
+use crate::{
+  handler::Envelope,
+  network::{
+    frame::{FrameAddress, Header},
+    type_hash, Network, NetworkEvent, NetworkStats,
+  },
+};
+
+/// The environment variable [`Network::new`] reads the key from, as 64 hex characters.
+pub const KEY_VARIABLE: &str = "ARBITER_NETWORK_KEY";
+
+const NONCE_LEN: usize = 12;
+
+pub struct Sealed<N: Network<Payload = Vec<u8>>> {
+  inner:   N,
+  key:     [u8; 32],
+  cipher:  ChaCha20Poly1305,
+  address: Option<N::Address>,
+}
+
+impl<N: Network<Payload = Vec<u8>> + Debug> Debug for Sealed<N> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Sealed").field("inner", &self.inner).finish_non_exhaustive()
+  }
+}
+
+impl<N: Network<Payload = Vec<u8>>> Sealed<N> {
+  /// Wraps `inner`, sealing payloads with `key` on this network and every network joined from it.
+  pub fn with_key(inner: N, key: [u8; 32]) -> Self {
+    Self { inner, key, cipher: ChaCha20Poly1305::new(Key::from_slice(&key)), address: None }
+  }
+
+  pub const fn inner(&self) -> &N { &self.inner }
+}
+
+/// What a sealed payload is bound to besides its key: the message type's tag and the frame header.
+fn associated_data<N>(envelope: &Envelope<N>) -> Vec<u8>
+where
+  N: Network,
+  N::Address: FrameAddress, {
+  let mut aad = type_hash(envelope.type_id).to_be_bytes().to_vec();
+  Header::of(envelope).write(&mut aad);
+  aad
+}
+
+fn seal(cipher: &ChaCha20Poly1305, aad: &[u8], plaintext: &[u8]) -> Option<Vec<u8>> {
+  let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+  let ciphertext = cipher.encrypt(&nonce, Payload { msg: plaintext, aad }).ok()?;
+  let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+  sealed.extend_from_slice(&nonce);
+  sealed.extend_from_slice(&ciphertext);
+  Some(sealed)
+}
+
+fn open(cipher: &ChaCha20Poly1305, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+  if sealed.len() < NONCE_LEN {
+    return None;
+  }
+  let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+  cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad }).ok()
+}
+
+fn parse_key(hex: &str) -> Option<[u8; 32]> {
+  let hex = hex.trim();
+  // `from_str_radix` would also accept a sign, as in `+f`.
+  if hex.len() != 64 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+    return None;
+  }
+  let mut key = [0u8; 32];
+  for (i, byte) in key.iter_mut().enumerate() {
+    *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+  }
+  Some(key)
+}
+
+impl<N> Network for Sealed<N>
+where
+  N: Network<Payload = Vec<u8>>,
+  N::Address: FrameAddress,
+{
+  type Address = N::Address;
+  type Payload = Vec<u8>;
+
+  /// Wraps a new `N` with the key in [`KEY_VARIABLE`].
+  ///
+  /// # Panics
+  ///
+  /// If the variable is unset or is not 64 hex characters, since there is no safe default key.
+  fn new() -> Self {
+    let key = std::env::var(KEY_VARIABLE)
+      .ok()
+      .and_then(|hex| parse_key(&hex))
+      .unwrap_or_else(|| panic!("{KEY_VARIABLE} must be set to a 32-byte hex key"));
+    Self::with_key(N::new(), key)
+  }
+
+  fn join(&self) -> Self { Self::with_key(self.inner.join(), self.key) }
+
+  fn bind(&mut self, address: Self::Address) {
+    self.address = Some(address);
+    self.inner.bind(address);
+  }
+
+  async fn send(&self, mut envelope: Envelope<Self>) {
+    // Stamped here rather than below, where it would no longer match what was sealed.
+    envelope.from = envelope.from.or(self.address);
+    let aad = associated_data(&envelope);
+    let Some(sealed) = seal(&self.cipher, &aad, &envelope.payload) else {
+      tracing::error!("failed to seal {:?}", envelope.type_id);
+      return;
+    };
+    self.inner.send(envelope.map_payload(|_| sealed)).await;
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    loop {
+      let envelope = self.inner.receive().await?;
+      match open(&self.cipher, &associated_data(&envelope), &envelope.payload) {
+        Some(opened) => return Some(envelope.map_payload(|_| opened)),
+        None => tracing::warn!("dropping envelope that failed authentication"),
+      }
+    }
+  }
+
+  fn take_events(&mut self) -> Vec<NetworkEvent> { self.inner.take_events() }
+
+  fn event_signal(&self) -> Option<std::sync::Arc<tokio::sync::Notify>> {
+    self.inner.event_signal()
+  }
+
+  async fn flush(&self) { self.inner.flush().await; }
+
+  fn stats(&self) -> NetworkStats { self.inner.stats() }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::any::TypeId;
+
+  use super::*;
+  use crate::{
+    fixtures::{Wire, WireAddress},
+    handler::Delivery,
+    network::Generateable,
+  };
+
+  #[test]
+  fn test_parse_key() {
+    assert_eq!(parse_key(&"0f".repeat(32)), Some([0x0f; 32]));
+    assert!(parse_key("0f0f").is_none());
+    assert!(parse_key(&"zz".repeat(32)).is_none());
+    assert!(parse_key(&"+f".repeat(32)).is_none());
+  }
+
+  #[test]
+  fn test_sealed_payloads_are_authenticated() {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&[7; 32]));
+    let mut envelope = Envelope::<Wire>::new(Vec::new(), TypeId::of::<u8>());
+    envelope.from = Some(WireAddress::generate());
+    let aad = associated_data(&envelope);
+    let sealed = seal(&cipher, &aad, b"secret").unwrap();
+    assert_eq!(open(&cipher, &aad, &sealed).unwrap(), b"secret");
+
+    // A different key or a flipped bit fail to open.
+    let other = ChaCha20Poly1305::new(Key::from_slice(&[8; 32]));
+    assert!(open(&other, &aad, &sealed).is_none());
+    let mut tampered = sealed.clone();
+    tampered[NONCE_LEN] ^= 1;
+    assert!(open(&cipher, &aad, &tampered).is_none());
+
+    // So does an envelope relabelled as another type, resent as someone else, or readdressed.
+    let relabelled = Envelope::<Wire> { type_id: TypeId::of::<u16>(), ..envelope.clone() };
+    let mut resent = envelope.clone();
+    resent.from = Some(WireAddress::generate());
+    let readdressed = envelope.with_delivery(Delivery::Unicast(WireAddress::generate()));
+    for altered in [relabelled, resent, readdressed] {
+      assert!(open(&cipher, &associated_data(&altered), &sealed).is_none());
+    }
+  }
+}
@@ -0,0 +1,382 @@
+//! A [`Network`] decorator that delays, drops, and partitions traffic.
+//!
+//! [`SimulatedNetwork`] wraps any other network and applies [`LinkConditions`] to every envelope as
+//! it is received: the envelope is dropped with probability `loss`, or held for `latency` plus or
+//! minus up to `jitter` before it is handed over. Because each envelope gets its own delay, jitter
+//! also reorders traffic. Conditions can be set for the network as a whole or for a single link
+//! between two addresses, and [`Conditions::partition`] cuts groups of addresses off from each
+//! other until [`Conditions::heal`] is called. Every connection joined from the same network shares
+//! one [`Conditions`], so a test can reshape the network while agents are running.
+//!
+//! Link conditions and partitions need to know who sent an envelope. The decorator stamps
+//! [`Envelope::from`] with its bound address on send, so traffic between simulated connections is
+//! always attributed; envelopes without a sender only see the receiver's default conditions.
+
+use std::{
+  cmp::Reverse,
+  collections::{BinaryHeap, HashMap, HashSet},
+  fmt::Debug,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+  },
+  time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+  handler::Envelope,
+  network::{Network, NetworkEvent, NetworkStats},
+};
+
+/// How envelopes travelling over a link are treated.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkConditions {
+  /// The base delay before an envelope is delivered.
+  pub latency: Duration,
+  /// The most the delay can vary from `latency` in either direction, chosen uniformly.
+  pub jitter:  Duration,
+  /// The probability, from `0.0` to `1.0`, that an envelope is dropped.
+  pub loss:    f64,
+}
+
+impl LinkConditions {
+  pub const fn new(latency: Duration) -> Self {
+    Self { latency, jitter: Duration::ZERO, loss: 0.0 }
+  }
+
+  pub const fn with_jitter(mut self, jitter: Duration) -> Self {
+    self.jitter = jitter;
+    self
+  }
+
+  pub const fn with_loss(mut self, loss: f64) -> Self {
+    self.loss = loss;
+    self
+  }
+}
+
+#[derive(Debug)]
+struct Table<A> {
+  default:    LinkConditions,
+  links:      HashMap<(A, A), LinkConditions>,
+  partitions: Vec<HashSet<A>>,
+}
+
+/// The conditions shared by every connection of a [`SimulatedNetwork`].
+#[derive(Debug)]
+pub struct Conditions<A> {
+  table: RwLock<Table<A>>,
+  seed:  u64,
+  joins: AtomicU64,
+}
+
+impl<A: Copy + Eq + std::hash::Hash> Conditions<A> {
+  fn new(seed: u64) -> Self {
+    Self {
+      table: RwLock::new(Table {
+        default:    LinkConditions::default(),
+        links:      HashMap::new(),
+        partitions: Vec::new(),
+      }),
+      seed,
+      joins: AtomicU64::new(0),
+    }
+  }
+
+  /// Sets the conditions for links without their own.
+  pub fn set_default(&self, conditions: LinkConditions) {
+    self.table.write().unwrap().default = conditions;
+  }
+
+  /// Sets the conditions for envelopes sent from `from` to `to`.
+  pub fn set_link(&self, from: A, to: A, conditions: LinkConditions) {
+    self.table.write().unwrap().links.insert((from, to), conditions);
+  }
+
+  /// Returns the link from `from` to `to` to the default conditions.
+  pub fn clear_link(&self, from: A, to: A) {
+    self.table.write().unwrap().links.remove(&(from, to));
+  }
+
+  /// Cuts `group` off from every address outside it. Addresses that are not in any group can only
+  /// reach each other.
+  pub fn partition(&self, group: impl IntoIterator<Item = A>) {
+    let group: HashSet<A> = group.into_iter().collect();
+    let mut table = self.table.write().unwrap();
+    for existing in &mut table.partitions {
+      existing.retain(|address| !group.contains(address));
+    }
+    table.partitions.retain(|existing| !existing.is_empty());
+    table.partitions.push(group);
+  }
+
+  /// Removes every partition.
+  pub fn heal(&self) { self.table.write().unwrap().partitions.clear(); }
+
+  /// Whether envelopes from `from` can currently reach `to`.
+  pub fn connected(&self, from: A, to: A) -> bool {
+    let table = self.table.read().unwrap();
+    let group_of = |address| table.partitions.iter().position(|group| group.contains(&address));
+    group_of(from) == group_of(to)
+  }
+
+  fn link(&self, from: Option<A>, to: Option<A>) -> Option<LinkConditions> {
+    if let (Some(from), Some(to)) = (from, to) {
+      if !self.connected(from, to) {
+        return None;
+      }
+      if let Some(link) = self.table.read().unwrap().links.get(&(from, to)) {
+        return Some(*link);
+      }
+    }
+    Some(self.table.read().unwrap().default)
+  }
+}
+
+/// A small SplitMix64 generator, so runs with the same seed drop and delay the same envelopes.
+#[derive(Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+  fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+
+  /// A float in `[0, 1)`.
+  fn next_f64(&mut self) -> f64 { (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64 }
+}
+
+struct Pending<N: Network> {
+  deliver_at: Instant,
+  sequence:   u64,
+  envelope:   Envelope<N>,
+}
+
+impl<N: Network> PartialEq for Pending<N> {
+  fn eq(&self, other: &Self) -> bool { self.cmp(other).is_eq() }
+}
+
+impl<N: Network> Eq for Pending<N> {}
+
+impl<N: Network> PartialOrd for Pending<N> {
+  fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> { Some(self.cmp(other)) }
+}
+
+impl<N: Network> Ord for Pending<N> {
+  fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    (self.deliver_at, self.sequence).cmp(&(other.deliver_at, other.sequence))
+  }
+}
+
+pub struct SimulatedNetwork<N: Network> {
+  inner:      N,
+  address:    Option<N::Address>,
+  conditions: Arc<Conditions<N::Address>>,
+  rng:        SplitMix64,
+  pending:    BinaryHeap<Reverse<Pending<N>>>,
+  sequence:   u64,
+  dropped:    u64,
+}
+
+impl<N: Network + Debug> Debug for SimulatedNetwork<N> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("SimulatedNetwork")
+      .field("inner", &self.inner)
+      .field("address", &self.address)
+      .field("conditions", &self.conditions)
+      .field("pending", &self.pending.len())
+      .finish_non_exhaustive()
+  }
+}
+
+impl<N: Network> SimulatedNetwork<N> {
+  /// Wraps `inner`, seeding the random drops and delays of this network and every network joined
+  /// from it with `seed`.
+  pub fn with_seed(inner: N, seed: u64) -> Self {
+    Self {
+      inner,
+      address: None,
+      conditions: Arc::new(Conditions::new(seed)),
+      rng: SplitMix64(seed),
+      pending: BinaryHeap::new(),
+      sequence: 0,
+      dropped: 0,
+    }
+  }
+
+  /// The conditions shared by every connection of this network.
+  pub fn conditions(&self) -> &Conditions<N::Address> { &self.conditions }
+
+  pub const fn inner(&self) -> &N { &self.inner }
+
+  fn schedule(&mut self, envelope: Envelope<N>) {
+    let Some(link) = self.conditions.link(envelope.from, self.address) else {
+      self.dropped += 1;
+      return;
+    };
+    if link.loss > 0.0 && self.rng.next_f64() < link.loss {
+      self.dropped += 1;
+      return;
+    }
+    let jitter = link.jitter.as_secs_f64() * self.rng.next_f64().mul_add(2.0, -1.0);
+    let delay = Duration::from_secs_f64((link.latency.as_secs_f64() + jitter).max(0.0));
+    self.sequence += 1;
+    self.pending.push(Reverse(Pending {
+      deliver_at: Instant::now() + delay,
+      sequence: self.sequence,
+      envelope,
+    }));
+  }
+
+  fn pop_due(&mut self) -> Option<Envelope<Self>> {
+    if self.pending.peek()?.0.deliver_at > Instant::now() {
+      return None;
+    }
+    Some(self.pending.pop()?.0.envelope.map_payload(std::convert::identity))
+  }
+}
+
+impl<N: Network> Network for SimulatedNetwork<N> {
+  type Address = N::Address;
+  type Payload = N::Payload;
+
+  fn new() -> Self { Self::with_seed(N::new(), 0) }
+
+  fn join(&self) -> Self {
+    // Give every joined connection its own stream of random numbers.
+    let joins = self.conditions.joins.fetch_add(1, Ordering::Relaxed) + 1;
+    let stream = SplitMix64(self.conditions.seed ^ joins).next_u64();
+    Self {
+      inner:      self.inner.join(),
+      address:    None,
+      conditions: self.conditions.clone(),
+      rng:        SplitMix64(stream),
+      pending:    BinaryHeap::new(),
+      sequence:   0,
+      dropped:    0,
+    }
+  }
+
+  fn bind(&mut self, address: Self::Address) {
+    self.address = Some(address);
+    self.inner.bind(address);
+  }
+
+  async fn send(&self, envelope: Envelope<Self>) {
+    let mut envelope: Envelope<N> = envelope.map_payload(std::convert::identity);
+    envelope.from = envelope.from.or(self.address);
+    self.inner.send(envelope).await;
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    loop {
+      if let Some(envelope) = self.pop_due() {
+        return Some(envelope);
+      }
+      let next = self.pending.peek().map(|pending| pending.0.deliver_at);
+      tokio::select! {
+        envelope = self.inner.receive() => match (envelope, next) {
+          (Some(envelope), _) => self.schedule(envelope),
+          (None, None) => return None,
+          (None, Some(next)) => tokio::time::sleep_until(next).await,
+        },
+        () = tokio::time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {},
+      }
+    }
+  }
+
+  fn take_events(&mut self) -> Vec<NetworkEvent> { self.inner.take_events() }
+
+  fn event_signal(&self) -> Option<std::sync::Arc<tokio::sync::Notify>> {
+    self.inner.event_signal()
+  }
+
+  async fn flush(&self) { self.inner.flush().await; }
+
+  /// Envelopes held back by latency count towards the queue depth, and envelopes lost or cut off
+  /// by a partition count as dropped.
+  fn stats(&self) -> NetworkStats {
+    let inner = self.inner.stats();
+    NetworkStats {
+      queue_depth: inner.queue_depth + self.pending.len(),
+      dropped: inner.dropped + self.dropped,
+      ..inner
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    fixtures::NumberMessage,
+    network::{
+      memory::{InMemory, InMemoryAddress},
+      Generateable,
+    },
+  };
+
+  fn pair() -> (SimulatedNetwork<InMemory>, SimulatedNetwork<InMemory>) {
+    let network = SimulatedNetwork::<InMemory>::with_seed(InMemory::new(), 7);
+    let mut alice = network.join();
+    alice.bind(InMemoryAddress::generate());
+    let mut bob = network.join();
+    bob.bind(InMemoryAddress::generate());
+    (alice, bob)
+  }
+
+  #[tokio::test]
+  async fn test_latency_delays_delivery() {
+    let (alice, mut bob) = pair();
+    alice.conditions().set_default(LinkConditions::new(Duration::from_millis(50)));
+
+    let start = Instant::now();
+    alice.send(Envelope::package(NumberMessage { value: 1 })).await;
+    let received = bob.receive().await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(received.unpackage::<NumberMessage>().unwrap().value, 1);
+    assert_eq!(received.from, alice.address);
+  }
+
+  #[tokio::test]
+  async fn test_partition_and_heal() {
+    let (alice, mut bob) = pair();
+    alice.conditions().partition([alice.address.unwrap()]);
+    assert!(!alice.conditions().connected(alice.address.unwrap(), bob.address.unwrap()));
+
+    alice.send(Envelope::package(NumberMessage { value: 1 })).await;
+    let received = tokio::time::timeout(Duration::from_millis(20), bob.receive()).await;
+    assert!(received.is_err());
+
+    alice.conditions().heal();
+    alice.send(Envelope::package(NumberMessage { value: 2 })).await;
+    let received = bob.receive().await.unwrap();
+    assert_eq!(received.unpackage::<NumberMessage>().unwrap().value, 2);
+  }
+
+  #[tokio::test]
+  async fn test_total_loss_drops_everything() {
+    let (alice, mut bob) = pair();
+    let bob_address = bob.address.unwrap();
+    alice.conditions().set_link(
+      alice.address.unwrap(),
+      bob_address,
+      LinkConditions::default().with_loss(1.0),
+    );
+
+    alice.send(Envelope::package(NumberMessage { value: 1 })).await;
+    let received = tokio::time::timeout(Duration::from_millis(20), bob.receive()).await;
+    assert!(received.is_err());
+
+    alice.conditions().clear_link(alice.address.unwrap(), bob_address);
+    alice.send(Envelope::package(NumberMessage { value: 2 })).await;
+    let received = bob.receive().await.unwrap();
+    assert_eq!(received.unpackage::<NumberMessage>().unwrap().value, 2);
+  }
+}
@@ -0,0 +1,150 @@
+//! Stable tags for message types, for transports that carry bytes between processes.
+//!
+//! A `TypeId` only means something inside the build that produced it, so byte transports tag each
+//! payload with a hash of its message type instead. Without registration that hash is still derived
+//! from the `TypeId`, so every peer has to be the same build. Registering a type in
+//! [`TypeTags::global`] under a name ties its tag to that name: peers that register the same names
+//! agree on tags across processes, builds, and crate versions, and every byte transport resolves a
+//! registered tag back to the local type before the agent looks up a handler for it.
+
+use std::{
+  any::TypeId,
+  collections::HashMap,
+  sync::{OnceLock, RwLock},
+};
+
+use crate::handler::Message;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum TagError {
+  #[error("the name `{0}` is already registered to another message type")]
+  NameTaken(String),
+  #[error("the message type is already registered as `{0}`")]
+  TypeTaken(String),
+  #[error("the name `{name}` hashes to the same tag as `{existing}`")]
+  Collision { name: String, existing: String },
+}
+
+#[derive(Debug, Default)]
+struct Table {
+  types: HashMap<u64, (TypeId, String)>,
+  tags:  HashMap<TypeId, u64>,
+}
+
+/// A table of message types and the names they are tagged with.
+#[derive(Debug, Default)]
+pub struct TypeTags {
+  table: RwLock<Table>,
+}
+
+impl TypeTags {
+  pub fn new() -> Self { Self::default() }
+
+  /// The table byte transports consult when tagging and resolving payloads.
+  pub fn global() -> &'static Self {
+    static GLOBAL: OnceLock<TypeTags> = OnceLock::new();
+    GLOBAL.get_or_init(Self::new)
+  }
+
+  /// Tags `M` with `name`. Registering the same type under the same name again does nothing.
+  pub fn register<M: Message>(&self, name: impl Into<String>) -> Result<u64, TagError> {
+    let (name, type_id) = (name.into(), TypeId::of::<M>());
+    let tag = tag_of_name(&name);
+    let mut table = self.table.write().unwrap();
+    if let Some(&existing) = table.tags.get(&type_id) {
+      let (_, existing_name) = &table.types[&existing];
+      return if *existing_name == name {
+        Ok(tag)
+      } else {
+        Err(TagError::TypeTaken(existing_name.clone()))
+      };
+    }
+    if let Some((_, existing)) = table.types.get(&tag) {
+      return Err(if *existing == name {
+        TagError::NameTaken(name)
+      } else {
+        TagError::Collision { name, existing: existing.clone() }
+      });
+    }
+    table.types.insert(tag, (type_id, name));
+    table.tags.insert(type_id, tag);
+    Ok(tag)
+  }
+
+  /// Tags `M` with its Rust path, as given by [`std::any::type_name`]. The path is stable across
+  /// builds as long as the type is not moved or renamed.
+  pub fn register_type<M: Message>(&self) -> Result<u64, TagError> {
+    self.register::<M>(std::any::type_name::<M>())
+  }
+
+  /// The tag `type_id` is registered with.
+  pub fn tag_of(&self, type_id: TypeId) -> Option<u64> {
+    self.table.read().unwrap().tags.get(&type_id).copied()
+  }
+
+  /// The message type registered with `tag`.
+  pub fn type_of(&self, tag: u64) -> Option<TypeId> {
+    self.table.read().unwrap().types.get(&tag).map(|(type_id, _)| *type_id)
+  }
+
+  /// The name the message type with `tag` is registered under.
+  pub fn name_of(&self, tag: u64) -> Option<String> {
+    self.table.read().unwrap().types.get(&tag).map(|(_, name)| name.clone())
+  }
+}
+
+/// The tag for `name`: its 64-bit FNV-1a hash, which unlike the standard library's hasher is fixed
+/// across Rust versions and platforms.
+pub fn tag_of_name(name: &str) -> u64 {
+  name.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+    (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    fixtures::{NumberMessage, TextMessage, Wire},
+    handler::Envelope,
+    network::{tagged_type, Network},
+  };
+
+  #[test]
+  fn test_register_and_resolve_tags() {
+    let tags = TypeTags::new();
+    let tag = tags.register::<TextMessage>("text").unwrap();
+    assert_eq!(tag, tag_of_name("text"));
+    assert_eq!(tags.register::<TextMessage>("text"), Ok(tag));
+    assert_eq!(tags.register::<TextMessage>("other"), Err(TagError::TypeTaken("text".to_string())));
+    let taken = tags.register::<NumberMessage>("text");
+    assert_eq!(taken, Err(TagError::NameTaken("text".to_string())));
+
+    assert_eq!(tags.tag_of(TypeId::of::<TextMessage>()), Some(tag));
+    assert_eq!(tags.type_of(tag), Some(TypeId::of::<TextMessage>()));
+    assert_eq!(tags.name_of(tag).as_deref(), Some("text"));
+    assert_eq!(tags.type_of(tag_of_name("number")), None);
+
+    // FNV-1a test vectors.
+    assert_eq!(tag_of_name(""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(tag_of_name("a"), 0xaf63_dc4c_8601_ec8c);
+  }
+
+  #[tokio::test]
+  async fn test_transports_resolve_registered_tags_first() {
+    #[derive(Debug)]
+    struct Registered;
+
+    let tag = TypeTags::global().register::<Registered>("arbiter.tests.registered").unwrap();
+    // A peer's hashed tag for another type that happens to collide with the registered one.
+    let types = RwLock::new(HashMap::from([(tag, TypeId::of::<NumberMessage>())]));
+    assert_eq!(tagged_type(&types, tag), Some(TypeId::of::<Registered>()));
+
+    let sender = Wire::new();
+    let mut receiver = sender.join();
+    sender.send(Envelope::new(b"registered".to_vec(), TypeId::of::<Registered>())).await;
+    receiver.types.write().unwrap().insert(tag, TypeId::of::<NumberMessage>());
+    let envelope = receiver.receive().await.unwrap();
+    assert_eq!(envelope.type_id, TypeId::of::<Registered>());
+  }
+}
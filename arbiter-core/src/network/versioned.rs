@@ -0,0 +1,271 @@
+//! A [`Network`] decorator that stamps byte payloads with the protocol version they were written
+//! in.
+//!
+//! Byte transports deserialize whatever arrives as the message type its tag names, so a peer built
+//! from a different version of a message type, or with a different codec, produces garbage or
+//! silent deserialization failures. [`Versioned`] prefixes every payload with a header carrying a
+//! [`ProtocolVersion`] and checks it on receive. Envelopes from incompatible peers are dropped, the
+//! first [`VersionError`] is kept for [`Versioned::incompatibility`], and each newly seen
+//! incompatible version is reported with `tracing::error!` and as a
+//! [`NetworkEvent::Incompatible`], so a mixed-version deployment fails loudly on its first message
+//! instead of garbling state.
+//!
+//! This is not a handshake. Joining a network does not reach any particular peer, so there is no
+//! point at which versions could be exchanged, and a mismatched peer joins without complaint and is
+//! only noticed when its first envelope arrives.
+//!
+//! Every peer on the underlying network must be versioned too, since plain payloads are rejected as
+//! [`VersionError::Unversioned`].
+
+use std::{borrow::Cow, fmt::Debug};
+
+use crate::{
+  handler::Envelope,
+  network::{Network, NetworkEvent, NetworkStats},
+};
+
+const MAGIC: &[u8; 3] = b"ARB";
+
+/// The codec the `Vec<u8>` [`Package`](crate::handler::Package) implementation writes.
+pub const JSON_CODEC: &str = "json";
+
+/// What a peer needs to agree on to read another peer's payloads.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProtocolVersion {
+  /// How messages are serialized, such as [`JSON_CODEC`].
+  pub codec:         Cow<'static, str>,
+  /// The version of the application's message types, bumped whenever one changes incompatibly.
+  pub schema:        u32,
+  /// The `arbiter-core` version, as major, minor, and patch.
+  pub crate_version: [u16; 3],
+}
+
+impl ProtocolVersion {
+  /// This build of `arbiter-core` writing JSON payloads of the given schema version.
+  pub fn current(schema: u32) -> Self {
+    let part = |part: &str| part.parse().unwrap_or_default();
+    Self {
+      codec: Cow::Borrowed(JSON_CODEC),
+      schema,
+      crate_version: [
+        part(env!("CARGO_PKG_VERSION_MAJOR")),
+        part(env!("CARGO_PKG_VERSION_MINOR")),
+        part(env!("CARGO_PKG_VERSION_PATCH")),
+      ],
+    }
+  }
+
+  /// Whether payloads written under `remote` can be read under `self`. Codecs and schemas must
+  /// match exactly, and crate versions must be semver-compatible.
+  pub fn check(&self, remote: &Self) -> Result<(), VersionError> {
+    if self.codec != remote.codec {
+      let (local, remote) = (self.codec.to_string(), remote.codec.to_string());
+      return Err(VersionError::Codec { local, remote });
+    }
+    if self.schema != remote.schema {
+      return Err(VersionError::Schema { local: self.schema, remote: remote.schema });
+    }
+    let [major, minor, _] = self.crate_version;
+    let [remote_major, remote_minor, _] = remote.crate_version;
+    if major != remote_major || (major == 0 && minor != remote_minor) {
+      let (local, remote) = (self.crate_version, remote.crate_version);
+      return Err(VersionError::Crate { local, remote });
+    }
+    Ok(())
+  }
+
+  fn encode(&self) -> Vec<u8> {
+    let codec = self.codec.as_bytes();
+    let codec = &codec[..codec.len().min(usize::from(u8::MAX))];
+    let mut header = Vec::with_capacity(MAGIC.len() + 1 + codec.len() + 10);
+    header.extend_from_slice(MAGIC);
+    header.push(codec.len() as u8);
+    header.extend_from_slice(codec);
+    header.extend_from_slice(&self.schema.to_be_bytes());
+    for part in self.crate_version {
+      header.extend_from_slice(&part.to_be_bytes());
+    }
+    header
+  }
+
+  /// Splits a header off the front of `framed`, returning the version and the rest.
+  fn decode(framed: &[u8]) -> Option<(Self, &[u8])> {
+    let rest = framed.strip_prefix(MAGIC)?;
+    let (&len, rest) = rest.split_first()?;
+    let (codec, rest) = (rest.get(..usize::from(len))?, rest.get(usize::from(len)..)?);
+    let codec = String::from_utf8(codec.to_vec()).ok()?;
+    let schema = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?);
+    let mut crate_version = [0u16; 3];
+    for (i, part) in crate_version.iter_mut().enumerate() {
+      *part = u16::from_be_bytes(rest.get(4 + 2 * i..6 + 2 * i)?.try_into().ok()?);
+    }
+    Some((Self { codec: Cow::Owned(codec), schema, crate_version }, rest.get(10..)?))
+  }
+}
+
+impl std::fmt::Display for ProtocolVersion {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let [major, minor, patch] = self.crate_version;
+    write!(f, "{} schema {} (arbiter-core {major}.{minor}.{patch})", self.codec, self.schema)
+  }
+}
+
+#[derive(Debug, Clone, thiserror::Error, PartialEq, Eq)]
+pub enum VersionError {
+  #[error("the peer did not send a protocol version header")]
+  Unversioned,
+  #[error("the peer writes `{remote}` payloads but this side reads `{local}`")]
+  Codec { local: String, remote: String },
+  #[error("the peer uses message schema {remote} but this side uses {local}")]
+  Schema { local: u32, remote: u32 },
+  #[error("the peer runs arbiter-core {remote:?}, which is incompatible with {local:?}")]
+  Crate { local: [u16; 3], remote: [u16; 3] },
+}
+
+pub struct Versioned<N: Network<Payload = Vec<u8>>> {
+  inner:           N,
+  version:         ProtocolVersion,
+  header:          Vec<u8>,
+  rejected:        Vec<ProtocolVersion>,
+  incompatibility: Option<VersionError>,
+  events:          Vec<NetworkEvent>,
+}
+
+impl<N: Network<Payload = Vec<u8>> + Debug> Debug for Versioned<N> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Versioned")
+      .field("inner", &self.inner)
+      .field("version", &self.version)
+      .field("incompatibility", &self.incompatibility)
+      .finish_non_exhaustive()
+  }
+}
+
+impl<N: Network<Payload = Vec<u8>>> Versioned<N> {
+  /// Wraps `inner`, writing and accepting `version` on this network and every network joined from
+  /// it.
+  pub fn with_version(inner: N, version: ProtocolVersion) -> Self {
+    let header = version.encode();
+    Self { inner, version, header, rejected: Vec::new(), incompatibility: None, events: Vec::new() }
+  }
+
+  pub const fn version(&self) -> &ProtocolVersion { &self.version }
+
+  pub const fn inner(&self) -> &N { &self.inner }
+
+  /// Why the first envelope from an incompatible peer was rejected, if one has been received.
+  pub const fn incompatibility(&self) -> Option<&VersionError> { self.incompatibility.as_ref() }
+
+  fn reject(&mut self, remote: Option<ProtocolVersion>, error: VersionError) {
+    match remote {
+      Some(remote) if self.rejected.contains(&remote) => return,
+      Some(remote) => {
+        let local = &self.version;
+        tracing::error!("rejecting a peer speaking {remote} as this side speaks {local}: {error}");
+        self.events.push(NetworkEvent::Incompatible { reason: error.to_string() });
+        self.rejected.push(remote);
+      },
+      None => tracing::debug!("dropping envelope: {error}"),
+    }
+    self.incompatibility.get_or_insert(error);
+  }
+}
+
+impl<N: Network<Payload = Vec<u8>>> Network for Versioned<N> {
+  type Address = N::Address;
+  type Payload = Vec<u8>;
+
+  /// Wraps a new `N` speaking schema version 0 of this build.
+  fn new() -> Self { Self::with_version(N::new(), ProtocolVersion::current(0)) }
+
+  fn join(&self) -> Self { Self::with_version(self.inner.join(), self.version.clone()) }
+
+  fn bind(&mut self, address: Self::Address) { self.inner.bind(address); }
+
+  async fn send(&self, envelope: Envelope<Self>) {
+    let header = &self.header;
+    let envelope = envelope.map_payload(|payload| [header.as_slice(), &payload].concat());
+    self.inner.send(envelope).await;
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    loop {
+      let envelope = self.inner.receive().await?;
+      let Some((remote, payload)) = ProtocolVersion::decode(&envelope.payload) else {
+        self.reject(None, VersionError::Unversioned);
+        continue;
+      };
+      match self.version.check(&remote) {
+        Ok(()) => {
+          let payload = payload.to_vec();
+          return Some(envelope.map_payload(|_| payload));
+        },
+        Err(error) => self.reject(Some(remote), error),
+      }
+    }
+  }
+
+  fn take_events(&mut self) -> Vec<NetworkEvent> {
+    let mut events = self.inner.take_events();
+    events.append(&mut self.events);
+    events
+  }
+
+  fn event_signal(&self) -> Option<std::sync::Arc<tokio::sync::Notify>> {
+    self.inner.event_signal()
+  }
+
+  async fn flush(&self) { self.inner.flush().await; }
+
+  fn stats(&self) -> NetworkStats { self.inner.stats() }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::any::TypeId;
+
+  use super::*;
+  use crate::fixtures::Wire;
+
+  #[test]
+  fn test_header_round_trip_and_compatibility() {
+    let local = ProtocolVersion::current(3);
+    let framed = [local.encode().as_slice(), b"payload"].concat();
+    let (remote, payload) = ProtocolVersion::decode(&framed).unwrap();
+    assert_eq!(remote, local);
+    assert_eq!(payload, b"payload");
+    assert_eq!(local.check(&remote), Ok(()));
+
+    let newer_schema = ProtocolVersion { schema: 4, ..local.clone() };
+    assert_eq!(local.check(&newer_schema), Err(VersionError::Schema { local: 3, remote: 4 }));
+    let other_codec = ProtocolVersion { codec: Cow::Borrowed("bincode"), ..local.clone() };
+    assert!(matches!(local.check(&other_codec), Err(VersionError::Codec { .. })));
+    let release = |crate_version| ProtocolVersion { crate_version, ..local.clone() };
+    assert_eq!(release([0, 1, 9]).check(&release([0, 1, 0])), Ok(()));
+    let incompatible = |local: [u16; 3], remote| {
+      matches!(release(local).check(&release(remote)), Err(VersionError::Crate { .. }))
+    };
+    assert!(incompatible([0, 1, 9], [0, 2, 0]));
+    assert!(incompatible([1, 0, 0], [2, 0, 0]));
+
+    assert!(ProtocolVersion::decode(b"payload").is_none());
+  }
+
+  #[tokio::test]
+  async fn test_incompatible_peers_are_reported_once() {
+    let wire = Wire::new();
+    let mut receiver = Versioned::with_version(wire.join(), ProtocolVersion::current(1));
+    let newer = Versioned::with_version(wire.join(), ProtocolVersion::current(2));
+    let current = Versioned::with_version(wire.join(), ProtocolVersion::current(1));
+    let type_id = TypeId::of::<u8>();
+    for _ in 0..2 {
+      newer.send(Envelope::new(b"garbled".to_vec(), type_id)).await;
+    }
+    current.send(Envelope::new(b"payload".to_vec(), type_id)).await;
+
+    assert_eq!(receiver.receive().await.unwrap().payload, b"payload");
+    let error = VersionError::Schema { local: 1, remote: 2 };
+    assert_eq!(receiver.incompatibility(), Some(&error));
+    assert_eq!(receiver.take_events(), [NetworkEvent::Incompatible { reason: error.to_string() }]);
+  }
+}
@@ -0,0 +1,394 @@
+//! A [`Network`] over ZeroMQ sockets.
+//!
+//! Each connection uses one of two socket patterns, chosen with [`Pattern`]:
+//! - [`Pattern::PubSub`] publishes on a PUB socket and listens on a SUB socket, normally the two
+//!   sides of a forwarder such as [`serve_forwarder`].
+//! - [`Pattern::DealerRouter`] talks through a DEALER socket to a ROUTER such as [`serve_router`],
+//!   which forwards every message to every dealer it has heard from. Dealers send an empty hello
+//!   frame when they connect, so they are heard from before they first send.
+//!
+//! Messages are three-frame: the message's [tag](super::tags), a [header](super::frame) carrying
+//! the sender, signature and recipients, and the payload. Peers must register the same tags or be
+//! built from the same source to agree on them, and a connection can only hand back envelopes for
+//! registered types and types it has sent or [`Zmq::register`]ed.
+//!
+//! When a socket fails, receive reopens the sockets after a [`Backoff`], and messages sent while
+//! they are down wait in a bounded [`ResendQueue`] until they are back. Each loss and recovery is
+//! reported as a [`NetworkEvent`] as soon as it happens.
+
+use std::{
+  any::TypeId,
+  collections::HashMap,
+  sync::{Arc, RwLock},
+};
+
+use tokio::sync::Mutex;
+use zeromq::{
+  DealerSocket, PubSocket, RouterSocket, Socket, SocketRecv, SocketSend, SubSocket, ZmqError,
+  ZmqMessage,
+};
+
+use crate::{
+  handler::{Envelope, Message},
+  network::{
+    frame::{FrameAddress, Header},
+    reconnect::{Backoff, ResendQueue},
+    tagged_type, type_hash, EventLog, Generateable, Network, NetworkEvent, NetworkStats,
+  },
+};
+
+/// The forwarder endpoints used by [`Network::new`] when `ARBITER_ZMQ_PUBLISH` and
+/// `ARBITER_ZMQ_SUBSCRIBE` are not set.
+pub const DEFAULT_PUBLISH_ENDPOINT: &str = "tcp://127.0.0.1:5555";
+pub const DEFAULT_SUBSCRIBE_ENDPOINT: &str = "tcp://127.0.0.1:5556";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pattern {
+  /// Publish to `publish` and subscribe to `subscribe`.
+  PubSub { publish: String, subscribe: String },
+  /// Send and receive through the ROUTER at `router`.
+  DealerRouter { router: String },
+}
+
+enum Sockets {
+  PubSub { publisher: Mutex<PubSocket>, subscriber: Mutex<SubSocket> },
+  DealerRouter(Mutex<DealerSocket>),
+}
+
+impl Sockets {
+  async fn open(pattern: &Pattern) -> Result<Self, ZmqError> {
+    match pattern {
+      Pattern::PubSub { publish, subscribe } => {
+        let mut publisher = PubSocket::new();
+        publisher.connect(publish).await?;
+        let mut subscriber = SubSocket::new();
+        subscriber.connect(subscribe).await?;
+        subscriber.subscribe("").await?;
+        Ok(Self::PubSub { publisher: Mutex::new(publisher), subscriber: Mutex::new(subscriber) })
+      },
+      Pattern::DealerRouter { router } => {
+        let mut dealer = DealerSocket::new();
+        dealer.connect(router).await?;
+        dealer.send(hello()).await?;
+        Ok(Self::DealerRouter(Mutex::new(dealer)))
+      },
+    }
+  }
+
+  async fn send(&self, message: ZmqMessage) -> Result<(), ZmqError> {
+    match self {
+      Self::PubSub { publisher, .. } => publisher.lock().await.send(message).await,
+      Self::DealerRouter(dealer) => dealer.lock().await.send(message).await,
+    }
+  }
+
+  async fn recv(&self) -> Result<ZmqMessage, ZmqError> {
+    match self {
+      Self::PubSub { subscriber, .. } => subscriber.lock().await.recv().await,
+      Self::DealerRouter(dealer) => dealer.lock().await.recv().await,
+    }
+  }
+}
+
+/// The message a dealer introduces itself to its router with: a single empty frame.
+fn hello() -> ZmqMessage { ZmqMessage::from(Vec::<u8>::new()) }
+
+pub struct Zmq {
+  pattern:  Arc<Pattern>,
+  types:    Arc<RwLock<HashMap<u64, TypeId>>>,
+  sockets:  Mutex<Option<Arc<Sockets>>>,
+  pending:  std::sync::Mutex<ResendQueue<ZmqMessage>>,
+  backoff:  Backoff,
+  /// When receive may try to reopen the sockets, kept across cancelled receives.
+  retry_at: Option<tokio::time::Instant>,
+  events:   EventLog,
+}
+
+impl std::fmt::Debug for Zmq {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Zmq")
+      .field("pattern", &self.pattern)
+      .field("pending", &self.pending.lock().unwrap().len())
+      .finish_non_exhaustive()
+  }
+}
+
+impl Zmq {
+  /// Creates a network using the given socket pattern.
+  ///
+  /// Sockets are connected lazily on the first send or receive.
+  pub fn with_pattern(pattern: Pattern) -> Self {
+    Self::sharing_types(pattern, Arc::new(RwLock::new(HashMap::new())))
+  }
+
+  /// Creates a network that shares this one's registered types but uses a different pattern.
+  pub fn join_with_pattern(&self, pattern: Pattern) -> Self {
+    Self::sharing_types(pattern, self.types.clone())
+  }
+
+  fn sharing_types(pattern: Pattern, types: Arc<RwLock<HashMap<u64, TypeId>>>) -> Self {
+    Self {
+      pattern: Arc::new(pattern),
+      types,
+      sockets: Mutex::new(None),
+      pending: std::sync::Mutex::new(ResendQueue::default()),
+      backoff: Backoff::default(),
+      retry_at: None,
+      events: EventLog::default(),
+    }
+  }
+
+  pub fn pattern(&self) -> &Pattern { &self.pattern }
+
+  /// Makes envelopes of type `M` receivable on this network and every network joined from it.
+  pub fn register<M: Message>(&self) {
+    let type_id = TypeId::of::<M>();
+    self.types.write().unwrap().insert(type_hash(type_id), type_id);
+  }
+
+  /// Returns the open sockets, opening them if there are none. Newly opened sockets are sent
+  /// everything queued while there were none.
+  async fn sockets(&self) -> Option<Arc<Sockets>> {
+    let mut slot = self.sockets.lock().await;
+    if let Some(sockets) = slot.as_ref() {
+      return Some(sockets.clone());
+    }
+    let sockets = match Sockets::open(&self.pattern).await {
+      Ok(sockets) => Arc::new(sockets),
+      Err(e) => {
+        tracing::error!("failed to connect ZeroMQ sockets for {:?}: {e}", self.pattern);
+        return None;
+      },
+    };
+    *slot = Some(sockets.clone());
+    self.events.record(NetworkEvent::Connected);
+    let pending: Vec<_> = self.pending.lock().unwrap().drain().collect();
+    for message in pending {
+      if let Err(e) = sockets.send(message).await {
+        tracing::warn!("failed to resend over ZeroMQ: {e}");
+      }
+    }
+    Some(sockets)
+  }
+
+  /// Drops the current sockets after a failure and schedules the next attempt to reopen them.
+  fn reconnect(&mut self, reason: String) {
+    if self.sockets.get_mut().take().is_some() {
+      self.events.record(NetworkEvent::Disconnected { reason });
+    }
+    let delay = self.backoff.next_delay();
+    let attempt = self.backoff.attempt();
+    self.retry_at = Some(tokio::time::Instant::now() + delay);
+    self.events.record(NetworkEvent::Reconnecting { attempt, delay });
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ZmqAddress(u64);
+
+impl ZmqAddress {
+  pub const fn from_u64(id: u64) -> Self { Self(id) }
+
+  pub const fn as_u64(&self) -> u64 { self.0 }
+}
+
+impl FrameAddress for ZmqAddress {
+  fn to_wire(&self) -> u64 { self.0 }
+
+  fn from_wire(id: u64) -> Self { Self(id) }
+}
+
+impl Generateable for ZmqAddress {
+  fn generate() -> Self {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    static COUNTER: AtomicU32 = AtomicU32::new(1);
+    // The process id keeps addresses from colliding between processes sharing a forwarder.
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    Self((u64::from(std::process::id()) << 32) | u64::from(id))
+  }
+}
+
+impl std::fmt::Display for ZmqAddress {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "zmq-{:016x}", self.0)
+  }
+}
+
+impl Network for Zmq {
+  type Address = ZmqAddress;
+  type Payload = Vec<u8>;
+
+  fn new() -> Self {
+    let publish =
+      std::env::var("ARBITER_ZMQ_PUBLISH").unwrap_or_else(|_| DEFAULT_PUBLISH_ENDPOINT.to_string());
+    let subscribe = std::env::var("ARBITER_ZMQ_SUBSCRIBE")
+      .unwrap_or_else(|_| DEFAULT_SUBSCRIBE_ENDPOINT.to_string());
+    Self::with_pattern(Pattern::PubSub { publish, subscribe })
+  }
+
+  fn join(&self) -> Self { self.join_with_pattern(self.pattern.as_ref().clone()) }
+
+  async fn send(&self, envelope: Envelope<Self>) {
+    let type_id = envelope.type_id;
+    let hash = type_hash(type_id);
+    self.types.write().unwrap().entry(hash).or_insert(type_id);
+    let mut message = ZmqMessage::from(hash.to_be_bytes().to_vec());
+    message.push_back(Header::of(&envelope).encode().into());
+    message.push_back(envelope.payload.into());
+    let Some(sockets) = self.sockets().await else {
+      tracing::warn!("ZeroMQ sockets are down, queueing message for resend");
+      self.pending.lock().unwrap().push(message);
+      return;
+    };
+    if let Err(e) = sockets.send(message.clone()).await {
+      tracing::warn!("failed to send over ZeroMQ, queueing message for resend: {e}");
+      self.pending.lock().unwrap().push(message);
+    }
+  }
+
+  async fn receive(&mut self) -> Option<Envelope<Self>> {
+    loop {
+      if let Some(retry_at) = self.retry_at {
+        tokio::time::sleep_until(retry_at).await;
+        self.retry_at = None;
+      }
+      let Some(sockets) = self.sockets().await else {
+        self.reconnect("failed to open sockets".to_string());
+        continue;
+      };
+      let message = match sockets.recv().await {
+        Ok(message) => message,
+        Err(e) => {
+          tracing::warn!("failed to receive over ZeroMQ, reconnecting: {e}");
+          self.reconnect(e.to_string());
+          continue;
+        },
+      };
+      self.backoff.reset();
+      let (Some(tag), Some(header), Some(payload)) =
+        (message.get(0), message.get(1), message.get(2))
+      else {
+        tracing::debug!("dropping malformed ZeroMQ message with {} frames", message.len());
+        continue;
+      };
+      let type_id = <[u8; 8]>::try_from(tag.as_ref())
+        .ok()
+        .and_then(|hash| tagged_type(&self.types, u64::from_be_bytes(hash)));
+      let Some(type_id) = type_id else {
+        tracing::debug!("dropping ZeroMQ message with unknown tag {tag:?}");
+        continue;
+      };
+      let Some(header) = Header::decode(header) else {
+        tracing::debug!("dropping ZeroMQ message with a malformed header");
+        continue;
+      };
+      let mut envelope = Envelope::new(payload.to_vec(), type_id);
+      header.apply(&mut envelope);
+      return Some(envelope);
+    }
+  }
+
+  fn take_events(&mut self) -> Vec<NetworkEvent> { self.events.take() }
+
+  fn event_signal(&self) -> Option<Arc<tokio::sync::Notify>> { Some(self.events.signal()) }
+
+  fn stats(&self) -> NetworkStats {
+    let pending = self.pending.lock().unwrap();
+    NetworkStats {
+      queue_depth: pending.len(),
+      dropped: pending.discarded(),
+      ..NetworkStats::default()
+    }
+  }
+}
+
+/// Binds a SUB socket on `publish` and a PUB socket on `subscribe`, and forwards everything
+/// published to the first to every subscriber of the second. Runs until a socket fails.
+pub async fn serve_forwarder(publish: &str, subscribe: &str) -> Result<(), ZmqError> {
+  let mut frontend = SubSocket::new();
+  frontend.bind(publish).await?;
+  frontend.subscribe("").await?;
+  let mut backend = PubSocket::new();
+  backend.bind(subscribe).await?;
+  loop {
+    let message = frontend.recv().await?;
+    backend.send(message).await?;
+  }
+}
+
+/// Binds a ROUTER socket on `endpoint` and forwards every message it receives to every dealer it
+/// has heard from, including the sender. Dealers that can no longer be reached are forgotten until
+/// they are heard from again. Runs until receiving fails.
+pub async fn serve_router(endpoint: &str) -> Result<(), ZmqError> {
+  let mut router = RouterSocket::new();
+  router.bind(endpoint).await?;
+  let mut dealers = Vec::new();
+  loop {
+    let mut frames = router.recv().await?.into_vec();
+    if frames.is_empty() {
+      continue;
+    }
+    let identity = frames.remove(0);
+    if !dealers.contains(&identity) {
+      dealers.push(identity);
+    }
+    // A hello only introduces the dealer.
+    if frames.iter().all(|frame| frame.is_empty()) {
+      continue;
+    }
+    let mut unreachable = Vec::new();
+    for dealer in &dealers {
+      let mut message = ZmqMessage::from(dealer.clone());
+      for frame in &frames {
+        message.push_back(frame.clone());
+      }
+      if let Err(e) = router.send(message).await {
+        tracing::warn!("failed to relay to ZeroMQ dealer {dealer:?}, forgetting it: {e}");
+        unreachable.push(dealer.clone());
+      }
+    }
+    dealers.retain(|dealer| !unreachable.contains(dealer));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+  use crate::fixtures::TextMessage;
+
+  #[tokio::test]
+  async fn test_router_relays_past_dead_dealers() {
+    // Find a free port for the router.
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let endpoint = format!("tcp://127.0.0.1:{port}");
+    let router = tokio::spawn({
+      let endpoint = endpoint.clone();
+      async move { serve_router(&endpoint).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let pattern = Pattern::DealerRouter { router: endpoint };
+
+    // A dealer that says hello and goes away.
+    let dead = Zmq::with_pattern(pattern.clone());
+    assert!(dead.sockets().await.is_some());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(dead);
+
+    // The receiver has only said hello, and still hears what the sender sends.
+    let sender = Zmq::with_pattern(pattern.clone());
+    let mut receiver = sender.join();
+    assert!(receiver.sockets().await.is_some());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let type_id = TypeId::of::<TextMessage>();
+    for _ in 0..2 {
+      sender.send(Envelope::new(b"hello".to_vec(), type_id)).await;
+      let envelope =
+        tokio::time::timeout(Duration::from_secs(5), receiver.receive()).await.unwrap().unwrap();
+      assert_eq!(envelope.type_id, type_id);
+      assert_eq!(envelope.payload, b"hello");
+    }
+    assert!(!router.is_finished());
+  }
+}
@@ -0,0 +1,216 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::clock::{ClockSource, TokioClock};
+
+/// What an agent does with an outbound message when its rate limit is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverLimitPolicy {
+  /// Hold the message in the agent's outbox until a token is available. The agent keeps handling
+  /// messages and control signals meanwhile, and later messages queue up behind it. Messages still
+  /// queued when the agent stops are discarded as if under [`OverLimitPolicy::Drop`].
+  ///
+  /// A queueing limit needs a positive `per_second`, or nothing queued would ever be sent.
+  Queue,
+  /// Discard the message, count it, emit a `tracing` warning, and tell the agent through
+  /// [`LifeCycle::on_rate_limited`](crate::agent::LifeCycle::on_rate_limited).
+  Drop,
+}
+
+/// A token-bucket limit on an agent's outbound messages.
+///
+/// The bucket holds up to `burst` tokens and gains `per_second` tokens every second of the agent's
+/// [`ClockSource`]. Every outbound message (start and stop messages as well as replies) spends one
+/// token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+  pub per_second: f64,
+  pub burst:      u32,
+  pub policy:     OverLimitPolicy,
+}
+
+impl RateLimit {
+  pub const fn new(per_second: f64, burst: u32) -> Self {
+    Self { per_second, burst, policy: OverLimitPolicy::Queue }
+  }
+
+  pub const fn with_policy(mut self, policy: OverLimitPolicy) -> Self {
+    self.policy = policy;
+    self
+  }
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+  limit:       RateLimit,
+  clock:       Arc<dyn ClockSource>,
+  tokens:      f64,
+  last_refill: Duration,
+  dropped:     u64,
+}
+
+impl TokenBucket {
+  /// # Panics
+  ///
+  /// See [`TokenBucket::with_clock`].
+  pub fn new(limit: RateLimit) -> Self { Self::with_clock(limit, Arc::new(TokioClock::new())) }
+
+  /// Creates a bucket that refills according to `clock`.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `limit` queues messages but never refills, i.e. its policy is
+  /// [`OverLimitPolicy::Queue`] and `per_second` is not positive.
+  pub fn with_clock(limit: RateLimit, clock: Arc<dyn ClockSource>) -> Self {
+    assert!(
+      limit.policy != OverLimitPolicy::Queue || limit.per_second > 0.0,
+      "a queueing rate limit needs a positive `per_second`, got {}",
+      limit.per_second
+    );
+    let last_refill = clock.now();
+    Self { limit, clock, tokens: f64::from(limit.burst), last_refill, dropped: 0 }
+  }
+
+  /// Switches the bucket to `clock`, keeping the tokens it currently holds.
+  pub fn set_clock(&mut self, clock: Arc<dyn ClockSource>) {
+    self.last_refill = clock.now();
+    self.clock = clock;
+  }
+
+  pub const fn limit(&self) -> RateLimit { self.limit }
+
+  /// The number of messages the limit has discarded.
+  pub const fn dropped(&self) -> u64 { self.dropped }
+
+  /// Counts `count` messages as discarded without going through the bucket, such as messages still
+  /// queued when their agent stops.
+  pub fn record_dropped(&mut self, count: u64) { self.dropped += count; }
+
+  /// Spends a token if one is available at `now`.
+  pub fn try_acquire_at(&mut self, now: Duration) -> bool {
+    self.refill(now);
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+
+  /// Spends a token if one is available now. Under [`OverLimitPolicy::Drop`] a miss is counted as
+  /// a dropped message.
+  pub fn try_acquire(&mut self) -> bool {
+    if self.try_acquire_at(self.clock.now()) {
+      return true;
+    }
+    if self.limit.policy == OverLimitPolicy::Drop {
+      self.dropped += 1;
+    }
+    false
+  }
+
+  /// When, by the bucket's clock, the next token will be available. This is in the past if one
+  /// already is, and [`Duration::MAX`] if the bucket never refills.
+  pub fn next_token_at(&self) -> Duration {
+    let missing = (1.0 - self.tokens).max(0.0);
+    if missing == 0.0 {
+      return self.last_refill;
+    }
+    if self.limit.per_second <= 0.0 {
+      return Duration::MAX;
+    }
+    let wait =
+      Duration::try_from_secs_f64(missing / self.limit.per_second).unwrap_or(Duration::MAX);
+    self.last_refill.saturating_add(wait)
+  }
+
+  /// Waits for a token under [`OverLimitPolicy::Queue`], or reports whether one was available
+  /// under [`OverLimitPolicy::Drop`].
+  ///
+  /// The wait follows the bucket's clock, so under a simulated clock it ends once the clock has
+  /// been moved far enough.
+  pub async fn acquire(&mut self) -> bool {
+    loop {
+      if self.try_acquire() {
+        return true;
+      }
+      match self.limit.policy {
+        OverLimitPolicy::Queue => self.clock.clone().sleep_until(self.next_token_at()).await,
+        OverLimitPolicy::Drop => return false,
+      }
+    }
+  }
+
+  fn refill(&mut self, now: Duration) {
+    let elapsed = now.saturating_sub(self.last_refill).as_secs_f64();
+    let refilled = elapsed.mul_add(self.limit.per_second, self.tokens);
+    self.tokens = refilled.min(f64::from(self.limit.burst));
+    self.last_refill = now;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::clock::ManualClock;
+
+  #[test]
+  fn test_bucket_refills_over_time() {
+    let mut bucket = TokenBucket::new(RateLimit::new(10.0, 2));
+    let start = bucket.last_refill;
+    assert!(bucket.try_acquire_at(start));
+    assert!(bucket.try_acquire_at(start));
+    assert!(!bucket.try_acquire_at(start));
+
+    // A tenth of a second buys exactly one more token.
+    assert!(bucket.try_acquire_at(start + Duration::from_millis(100)));
+    assert!(!bucket.try_acquire_at(start + Duration::from_millis(100)));
+
+    // The bucket never holds more than `burst` tokens.
+    let later = start + Duration::from_secs(10);
+    assert!(bucket.try_acquire_at(later));
+    assert!(bucket.try_acquire_at(later));
+    assert!(!bucket.try_acquire_at(later));
+  }
+
+  #[tokio::test]
+  async fn test_bucket_follows_its_clock() {
+    let clock = Arc::new(ManualClock::new(Duration::from_secs(1_000)));
+    let limit = RateLimit::new(1.0, 1).with_policy(OverLimitPolicy::Drop);
+    let mut bucket = TokenBucket::with_clock(limit, clock.clone());
+    assert!(bucket.acquire().await);
+    assert!(!bucket.acquire().await);
+
+    clock.advance(Duration::from_secs(1));
+    assert!(bucket.acquire().await);
+    assert_eq!(bucket.dropped(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_drop_policy_counts_dropped() {
+    let mut bucket = TokenBucket::new(RateLimit::new(0.0, 1).with_policy(OverLimitPolicy::Drop));
+    assert!(bucket.acquire().await);
+    assert!(!bucket.acquire().await);
+    assert!(!bucket.acquire().await);
+    assert_eq!(bucket.dropped(), 2);
+  }
+
+  #[tokio::test]
+  async fn test_queue_policy_waits_for_its_clock() {
+    let clock = Arc::new(ManualClock::new(Duration::from_secs(1_000)));
+    let mut bucket = TokenBucket::with_clock(RateLimit::new(2.0, 1), clock.clone());
+    assert!(bucket.acquire().await);
+    assert_eq!(bucket.next_token_at(), Duration::from_millis(1_000_500));
+
+    let waiter = tokio::spawn(async move { bucket.acquire().await });
+    tokio::task::yield_now().await;
+    assert!(!waiter.is_finished());
+    clock.advance(Duration::from_millis(500));
+    assert!(tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap());
+  }
+
+  #[test]
+  #[should_panic(expected = "positive `per_second`")]
+  fn test_queue_policy_rejects_a_limit_that_never_refills() {
+    TokenBucket::new(RateLimit::new(0.0, 1));
+  }
+}
@@ -0,0 +1,252 @@
+//! Ed25519 signatures tying envelopes to the address that sent them.
+//!
+//! An agent given a key with [`Agent::with_signing_key`](crate::agent::Agent::with_signing_key)
+//! stamps every envelope it sends with its own address and a [`Signature`] over that address, the
+//! message type, the recipients, and the payload. Receivers look the sender's public key up in a
+//! [`KeyDirectory`], and an agent given one with
+//! [`Agent::with_key_directory`](crate::agent::Agent::with_key_directory) drops every envelope
+//! that does not verify before any handler sees it. This is independent of transport encryption,
+//! and works the same on an [`InMemory`](crate::network::memory::InMemory) network, which makes it
+//! useful for simulations where some agents try to impersonate others.
+//!
+//! What a signature covers is given by [`SignedPayload`] and [`SignedAddress`]. Addresses are
+//! signed in full, so two senders cannot share a signature, and neither can an envelope readdressed
+//! to someone else on the way. Byte payloads are signed as they are, while in-process payloads are
+//! signed over their `Debug` representation, so message types sent between signing agents in
+//! process should print every field that matters. The message type is covered by its
+//! [tag](crate::network::tags), so peers must register the same tags to verify each other. Byte
+//! transports carry the sender, signature and recipients in their [frame
+//! header](crate::network::frame).
+//!
+//! There is no replay protection. A signature says who sent an envelope, not when, so anyone who
+//! sees a signed envelope can send it again and it will still verify. Receivers that must not act
+//! on the same envelope twice need to put something unique, such as a sequence number or a
+//! timestamp, in the message and check it themselves.
+
+use std::{
+  borrow::Cow,
+  collections::HashMap,
+  hash::Hash,
+  sync::{Arc, RwLock},
+};
+
+use ed25519_dalek::{Signer, Verifier};
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+#[cfg(feature = "in-memory")]
+use crate::network::memory::InMemoryAddress;
+use crate::{
+  handler::{Delivery, Envelope, Message, Signature},
+  network::{frame::FrameAddress, type_hash, Network},
+};
+
+/// A payload that can be signed.
+pub trait SignedPayload {
+  /// The bytes a signature over this payload covers.
+  fn signed_bytes(&self) -> Cow<'_, [u8]>;
+}
+
+impl SignedPayload for Vec<u8> {
+  fn signed_bytes(&self) -> Cow<'_, [u8]> { Cow::Borrowed(self) }
+}
+
+impl SignedPayload for Arc<dyn Message> {
+  fn signed_bytes(&self) -> Cow<'_, [u8]> { Cow::Owned(format!("{self:?}").into_bytes()) }
+}
+
+/// An address that can be signed.
+pub trait SignedAddress {
+  /// The bytes a signature naming this address covers, which must differ for every address.
+  fn signed_bytes(&self) -> Cow<'_, [u8]>;
+}
+
+impl<A: FrameAddress> SignedAddress for A {
+  fn signed_bytes(&self) -> Cow<'_, [u8]> { Cow::Owned(self.to_wire().to_be_bytes().to_vec()) }
+}
+
+#[cfg(feature = "in-memory")]
+impl SignedAddress for InMemoryAddress {
+  fn signed_bytes(&self) -> Cow<'_, [u8]> { Cow::Borrowed(self.as_bytes()) }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum VerifyError {
+  #[error("the envelope is not signed or does not name its sender")]
+  Unsigned,
+  #[error("no key is registered for the sender `{0}`")]
+  UnknownSender(String),
+  #[error("the signature does not match the sender's key")]
+  BadSignature,
+}
+
+/// Appends `address` to `message`, behind its length.
+fn push_address<A: SignedAddress>(message: &mut Vec<u8>, address: &A) {
+  let bytes = address.signed_bytes();
+  let len = u32::try_from(bytes.len()).expect("addresses are shorter than 4 GiB");
+  message.extend_from_slice(&len.to_be_bytes());
+  message.extend_from_slice(&bytes);
+}
+
+/// The sender, message type, recipients and payload, each in an unambiguous encoding.
+fn signed_message<N: Network>(envelope: &Envelope<N>, from: N::Address) -> Vec<u8>
+where
+  N::Address: SignedAddress,
+  N::Payload: SignedPayload, {
+  let mut message = Vec::new();
+  push_address(&mut message, &from);
+  message.extend_from_slice(&type_hash(envelope.type_id).to_be_bytes());
+  match &envelope.to {
+    Delivery::Broadcast => message.push(0),
+    Delivery::Unicast(to) => {
+      message.push(1);
+      push_address(&mut message, to);
+    },
+    Delivery::Multicast(to) => {
+      message.push(2);
+      let count = u32::try_from(to.len()).expect("multicasts are limited to 2^32 recipients");
+      message.extend_from_slice(&count.to_be_bytes());
+      for to in to {
+        push_address(&mut message, to);
+      }
+    },
+  }
+  message.extend_from_slice(&envelope.payload.signed_bytes());
+  message
+}
+
+/// Marks `envelope` as sent from `from` and signs it with `key`. The envelope must already be
+/// addressed, since the signature covers its recipients.
+pub fn sign<N: Network>(key: &SigningKey, envelope: &mut Envelope<N>, from: N::Address)
+where
+  N::Address: SignedAddress,
+  N::Payload: SignedPayload, {
+  envelope.from = Some(from);
+  let signature = key.sign(&signed_message(envelope, from));
+  envelope.signature = Some(Signature(signature.to_bytes()));
+}
+
+/// The public keys of the addresses on a network.
+#[derive(Debug)]
+pub struct KeyDirectory<A> {
+  keys: RwLock<HashMap<A, VerifyingKey>>,
+}
+
+impl<A> Default for KeyDirectory<A> {
+  fn default() -> Self { Self { keys: RwLock::new(HashMap::new()) } }
+}
+
+impl<A: Copy + Eq + Hash + std::fmt::Display + SignedAddress> KeyDirectory<A> {
+  pub fn new() -> Self { Self::default() }
+
+  /// Records `key` as the key envelopes from `address` must be signed with.
+  pub fn register(&self, address: A, key: VerifyingKey) {
+    self.keys.write().unwrap().insert(address, key);
+  }
+
+  pub fn unregister(&self, address: A) { self.keys.write().unwrap().remove(&address); }
+
+  pub fn key_of(&self, address: A) -> Option<VerifyingKey> {
+    self.keys.read().unwrap().get(&address).copied()
+  }
+
+  /// Checks that `envelope` is signed by the key registered for its sender.
+  pub fn verify<N>(&self, envelope: &Envelope<N>) -> Result<(), VerifyError>
+  where
+    N: Network<Address = A>,
+    N::Payload: SignedPayload, {
+    let (Some(from), Some(signature)) = (envelope.from, envelope.signature) else {
+      return Err(VerifyError::Unsigned);
+    };
+    let key = self.key_of(from).ok_or_else(|| VerifyError::UnknownSender(from.to_string()))?;
+    let signature = ed25519_dalek::Signature::from_bytes(&signature.0);
+    key.verify(&signed_message(envelope, from), &signature).map_err(|_| VerifyError::BadSignature)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    agent::Agent,
+    fixtures::{Logger, NumberMessage, Wire},
+    network::{
+      memory::{InMemory, InMemoryAddress},
+      Generateable,
+    },
+  };
+
+  #[test]
+  fn test_verify_rejects_spoofed_envelopes() {
+    let (alice, mallory) = (InMemoryAddress::generate(), InMemoryAddress::generate());
+    let alice_key = SigningKey::from_bytes(&[1; 32]);
+    let mallory_key = SigningKey::from_bytes(&[2; 32]);
+    let directory = KeyDirectory::new();
+    directory.register(alice, alice_key.verifying_key());
+    directory.register(mallory, mallory_key.verifying_key());
+
+    let mut envelope = Envelope::<InMemory>::package(NumberMessage { value: 1 });
+    assert_eq!(directory.verify(&envelope), Err(VerifyError::Unsigned));
+    sign(&alice_key, &mut envelope, alice);
+    assert_eq!(directory.verify(&envelope), Ok(()));
+
+    // Mallory signs with their own key but claims to be Alice.
+    let mut spoofed = Envelope::<InMemory>::package(NumberMessage { value: 1 });
+    sign(&mallory_key, &mut spoofed, alice);
+    assert_eq!(directory.verify(&spoofed), Err(VerifyError::BadSignature));
+
+    // Alice's signature does not carry over to a different payload.
+    let mut altered = Envelope::<InMemory>::package(NumberMessage { value: 2 });
+    altered.from = envelope.from;
+    altered.signature = envelope.signature;
+    assert_eq!(directory.verify(&altered), Err(VerifyError::BadSignature));
+
+    // Nor to other recipients: a unicast to Bob readdressed to Mallory fails.
+    let bob = InMemoryAddress::generate();
+    let mut unicast = Envelope::<InMemory>::package(NumberMessage { value: 1 })
+      .with_delivery(Delivery::Unicast(bob));
+    sign(&alice_key, &mut unicast, alice);
+    assert_eq!(directory.verify(&unicast), Ok(()));
+    let readdressed = unicast.clone().with_delivery(Delivery::Unicast(mallory));
+    assert_eq!(directory.verify(&readdressed), Err(VerifyError::BadSignature));
+    let widened = unicast.with_delivery(Delivery::Broadcast);
+    assert_eq!(directory.verify(&widened), Err(VerifyError::BadSignature));
+
+    // Nor to another sender that prints the same as Alice, even one holding the same key.
+    let mut bytes = *alice.as_bytes();
+    bytes[31] ^= 1;
+    let twin = InMemoryAddress::from_bytes(bytes);
+    assert_eq!(twin.to_string(), alice.to_string());
+    directory.register(twin, alice_key.verifying_key());
+    let mut impersonated = envelope.clone();
+    impersonated.from = Some(twin);
+    assert_eq!(directory.verify(&impersonated), Err(VerifyError::BadSignature));
+  }
+
+  #[tokio::test]
+  async fn test_signatures_verify_across_a_byte_network() {
+    let network = Wire::new();
+    let mut listener = network.join();
+    let key = SigningKey::from_bytes(&[1; 32]);
+    let agent = Agent::<Logger, _>::new_join_network(
+      Logger { name: "Signer".to_string(), message_count: 0 },
+      &network,
+    )
+    .with_signing_key(key.clone());
+    let directory = KeyDirectory::new();
+    directory.register(agent.address(), key.verifying_key());
+
+    let mut processing_agent = agent.process();
+    processing_agent.start().await;
+    let start = listener.receive().await.unwrap();
+    assert_eq!(directory.verify(&start), Ok(()));
+    processing_agent.stop().await;
+    processing_agent.join().await;
+    let stop = listener.receive().await.unwrap();
+    assert_eq!(directory.verify(&stop), Ok(()));
+
+    // A replayed envelope still verifies, since signatures do not say when they were made.
+    network.send(start).await;
+    let replayed = listener.receive().await.unwrap();
+    assert_eq!(directory.verify(&replayed), Ok(()));
+  }
+}
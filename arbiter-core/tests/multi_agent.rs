@@ -27,7 +27,7 @@ impl LifeCycle for Ping {
     PingMessage
   }
 
-  fn on_stop(&mut self) -> Self::StopMessage { StopMessage }
+  fn on_stop(&mut self, _reason: &ShutdownReason) -> Self::StopMessage { StopMessage }
 }
 
 impl Handler<PongMessage> for Ping {
@@ -52,7 +52,7 @@ impl LifeCycle for Pong {
 
   fn on_start(&mut self) -> Self::StartMessage {}
 
-  fn on_stop(&mut self) -> Self::StopMessage {}
+  fn on_stop(&mut self, _reason: &ShutdownReason) -> Self::StopMessage {}
 }
 
 impl Handler<PingMessage> for Pong {
@@ -86,4 +86,5 @@ async fn test_multi_agent() {
 
   let agent = ping.join().await;
   assert_eq!(agent.inner().count, 10);
+  assert_eq!(agent.shutdown_reason(), Some(&ShutdownReason::Halted));
 }